@@ -0,0 +1,210 @@
+use fax::{Color, VecWriter, encoder::Encoder};
+use image::{DynamicImage, GrayImage, Luma};
+
+/// A pixel is colored if its channels differ by more than this, which is above the color
+/// noise of scans and JPEG compression.
+const CHROMA_THRESHOLD: u8 = 40;
+/// A page with at most this fraction of colored pixels is gray.
+const MAX_COLORED: f64 = 0.001;
+/// A pixel is ink if it is this many percent darker than the mean of its neighbourhood.
+const INK_CONTRAST: u64 = 15;
+/// A pixel is also clearly ink if it is this many percent darker, and clearly paper if it is
+/// not darker than `INK_CONTRAST` and brighter than `PAPER_LEVEL`. Anything else is a shade.
+const DARK_CONTRAST: u64 = 40;
+const PAPER_LEVEL: u64 = 128;
+/// A page with at most this fraction of shades is text, which only has them at the edges of
+/// the strokes. Photos and shaded figures have many more.
+const MAX_SHADES: f64 = 0.08;
+
+/// What a page is stored as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PageKind {
+    Color,
+    Gray,
+    Bilevel,
+}
+
+/// Whether `img` is a color, a gray or a black and white text page.
+pub(crate) fn classify(img: &DynamicImage) -> PageKind {
+    if img.color().has_color() {
+        let rgb = img.to_rgb8();
+        let colored = rgb
+            .pixels()
+            .filter(|pixel| {
+                let [r, g, b] = pixel.0;
+                r.max(g).max(b) - r.min(g).min(b) > CHROMA_THRESHOLD
+            })
+            .count();
+        if colored as f64 > rgb.len() as f64 / 3.0 * MAX_COLORED {
+            return PageKind::Color;
+        }
+    }
+    let gray = img.to_luma8();
+    let mut shades = 0usize;
+    for_each_with_mean(&gray, |_, _, value, mean| {
+        let ink = value * 100 < mean * (100 - DARK_CONTRAST);
+        let paper = value * 100 >= mean * (100 - INK_CONTRAST) && value >= PAPER_LEVEL;
+        if !ink && !paper {
+            shades += 1;
+        }
+    });
+    if shades as f64 > gray.len() as f64 * MAX_SHADES {
+        PageKind::Gray
+    } else {
+        PageKind::Bilevel
+    }
+}
+
+/// Turns `gray` into black (0) and white (255) with the local mean as the threshold
+/// (Bradley's method), so uneven lighting and yellowed paper do not turn into black areas.
+pub(crate) fn binarize(gray: &GrayImage) -> GrayImage {
+    let mut output = GrayImage::new(gray.width(), gray.height());
+    for_each_with_mean(gray, |x, y, value, mean| {
+        let ink = value * 100 < mean * (100 - INK_CONTRAST);
+        output.put_pixel(x, y, Luma([if ink { 0 } else { 255 }]));
+    });
+    output
+}
+
+/// Calls `f` with every pixel and the mean of the pixels around it, in a window of about an
+/// eighth of the page, wider than the strokes of any text. The sums of the window are kept
+/// per column, so only one row of them is in memory.
+fn for_each_with_mean(gray: &GrayImage, mut f: impl FnMut(u32, u32, u64, u64)) {
+    let (width, height) = (gray.width() as usize, gray.height() as usize);
+    let radius = (width.max(height) / 16).max(1);
+    let level = |x: usize, y: usize| gray.get_pixel(x as u32, y as u32).0[0] as u64;
+    let mut column_sums = vec![0u64; width];
+    for y in 0..radius.min(height) {
+        for (x, sum) in column_sums.iter_mut().enumerate() {
+            *sum += level(x, y);
+        }
+    }
+    let mut row_prefix = vec![0u64; width + 1];
+    for y in 0..height {
+        // The window covers the rows top..=bottom
+        let bottom = y + radius;
+        if bottom < height {
+            for (x, sum) in column_sums.iter_mut().enumerate() {
+                *sum += level(x, bottom);
+            }
+        }
+        if y > radius {
+            for (x, sum) in column_sums.iter_mut().enumerate() {
+                *sum -= level(x, y - radius - 1);
+            }
+        }
+        let rows = (bottom.min(height - 1) - y.saturating_sub(radius) + 1) as u64;
+        for x in 0..width {
+            row_prefix[x + 1] = row_prefix[x] + column_sums[x];
+        }
+        for x in 0..width {
+            let (left, right) = (x.saturating_sub(radius), (x + radius).min(width - 1));
+            let count = (right - left + 1) as u64 * rows;
+            let sum = row_prefix[right + 1] - row_prefix[left];
+            f(x as u32, y as u32, level(x, y), sum / count);
+        }
+    }
+}
+
+/// Encodes a black and white image with CCITT Group 4, for the CCITTFaxDecode filter with
+/// `K -1`. Returns `None` if the page is wider than the encoder allows.
+pub(crate) fn encode_g4(bilevel: &GrayImage) -> Option<Vec<u8>> {
+    let width = u16::try_from(bilevel.width()).ok()?;
+    let mut encoder = Encoder::new(VecWriter::new());
+    for row in bilevel.rows() {
+        let pels = row.map(|pixel| {
+            if pixel.0[0] < 128 {
+                Color::Black
+            } else {
+                Color::White
+            }
+        });
+        let Ok(()) = encoder.encode_line(pels, width);
+    }
+    let Ok(writer) = encoder.finish();
+    Some(writer.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use fax::decoder::{decode_g4, pels};
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    const SIZE: u32 = 400;
+
+    /// Whether `(x, y)` is in a stroke of the lines of "text": 4 by 12 pixel blocks.
+    fn stroke(x: u32, y: u32) -> bool {
+        x % 12 < 4 && y % 30 < 12 && (20..SIZE - 20).contains(&x) && (20..SIZE - 20).contains(&y)
+    }
+
+    /// Black text on paper lit from the right, from 120 on the left to 250 on the right.
+    fn text_page() -> GrayImage {
+        GrayImage::from_fn(SIZE, SIZE, |x, y| {
+            let paper = 120 + 130 * x / (SIZE - 1);
+            Luma([if stroke(x, y) { paper / 2 } else { paper } as u8])
+        })
+    }
+
+    #[test]
+    fn classify_pages() {
+        let text = DynamicImage::ImageLuma8(text_page());
+        assert_eq!(classify(&text), PageKind::Bilevel);
+        // The same in RGB, like most scans
+        assert_eq!(
+            classify(&DynamicImage::ImageRgb8(text.to_rgb8())),
+            PageKind::Bilevel
+        );
+
+        let shaded = RgbImage::from_fn(SIZE, SIZE, |x, y| {
+            let level = ((x + y) * 255 / (2 * SIZE)) as u8;
+            Rgb([level, level, level])
+        });
+        assert_eq!(classify(&DynamicImage::ImageRgb8(shaded)), PageKind::Gray);
+
+        // A red figure on a text page
+        let mut color = text.to_rgb8();
+        for (x, y, pixel) in color.enumerate_pixels_mut() {
+            if (100..150).contains(&x) && (100..150).contains(&y) {
+                *pixel = Rgb([200, 30, 30]);
+            }
+        }
+        assert_eq!(classify(&DynamicImage::ImageRgb8(color)), PageKind::Color);
+    }
+
+    #[test]
+    fn binarize_uneven_lighting() {
+        let bits = binarize(&text_page());
+        for (x, y, pixel) in bits.enumerate_pixels() {
+            let expected = if stroke(x, y) { 0 } else { 255 };
+            assert_eq!(pixel.0[0], expected, "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn g4_round_trip() {
+        let bits = binarize(&text_page());
+        let data = encode_g4(&bits).unwrap();
+        let mut rows = Vec::new();
+        decode_g4(
+            data.into_iter(),
+            SIZE as u16,
+            Some(SIZE as u16),
+            |transitions| rows.push(pels(transitions, SIZE as u16).collect::<Vec<_>>()),
+        )
+        .unwrap();
+        assert_eq!(rows.len(), SIZE as usize);
+        for (y, row) in rows.iter().enumerate() {
+            for (x, color) in row.iter().enumerate() {
+                let expected = if bits.get_pixel(x as u32, y as u32).0[0] == 0 {
+                    Color::Black
+                } else {
+                    Color::White
+                };
+                assert_eq!(*color, expected, "({}, {})", x, y);
+            }
+        }
+        assert!(encode_g4(&GrayImage::new(70_000, 1)).is_none());
+    }
+}
@@ -0,0 +1,150 @@
+use reqwest::Url;
+
+use crate::error::Error;
+
+const HOST: &str = "ereserves.lib.tsinghua.edu.cn";
+/// Path segments that are followed by the book ID.
+const ID_SEGMENTS: [&str; 3] = ["bookDetail", "readBook", "reader"];
+/// Query parameters of share links that carry the book ID.
+const ID_PARAMS: [&str; 2] = ["bookId", "bookid"];
+
+fn is_id(s: &str) -> bool {
+    s.len() >= 8 && s.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Finds the ID in a path like `/bookDetail/<id>/`, or in a query like `?bookId=<id>`.
+fn find_in(url: &Url) -> Option<String> {
+    let segments: Vec<&str> = url.path_segments()?.filter(|s| !s.is_empty()).collect();
+    segments
+        .windows(2)
+        .find(|pair| ID_SEGMENTS.contains(&pair[0]) && is_id(pair[1]))
+        .map(|pair| pair[1].to_owned())
+        .or_else(|| {
+            url.query_pairs()
+                .find(|(key, value)| ID_PARAMS.contains(&key.as_ref()) && is_id(value))
+                .map(|(_, value)| value.into_owned())
+        })
+}
+
+/// Extracts the book ID from what users paste: the book detail page, the reader, a share
+/// link or the bare ID, with or without trailing slashes, queries and fragments.
+///
+/// ```
+/// use thubookrs::book_id::parse;
+///
+/// let url = "https://ereserves.lib.tsinghua.edu.cn/bookDetail/c01e1db11c4041a39db463e810bac8f94af518935a1ec46ef/";
+/// assert_eq!(parse(url).unwrap(), "c01e1db11c4041a39db463e810bac8f94af518935a1ec46ef");
+/// ```
+pub fn parse(input: &str) -> Result<String, Error> {
+    let input = input.trim();
+    if is_id(input) {
+        return Ok(input.to_owned());
+    }
+    let invalid = || Error::InvalidBookUrl(input.to_owned());
+    let url = if input.contains("://") {
+        Url::parse(input)
+    } else {
+        Url::parse(&format!("https://{}", input))
+    }
+    .map_err(|_| invalid())?;
+    if url.host_str() != Some(HOST) {
+        return Err(invalid());
+    }
+    find_in(&url)
+        .or_else(|| {
+            // Routes of the single page app live in the fragment, like `/#/bookDetail/<id>`.
+            find_in(&url.join(url.fragment()?).ok()?)
+        })
+        .ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "c01e1db11c4041a39db463e810bac8f94af518935a1ec46ef";
+
+    fn assert_parses(inputs: &[String]) {
+        for input in inputs {
+            assert_eq!(parse(input).unwrap(), ID, "{}", input);
+        }
+    }
+
+    #[test]
+    fn book_detail() {
+        assert_parses(&[
+            format!("https://{}/bookDetail/{}", HOST, ID),
+            format!("http://{}/bookDetail/{}", HOST, ID),
+            format!("{}/bookDetail/{}", HOST, ID),
+        ]);
+    }
+
+    #[test]
+    fn book_detail_with_trailing_slash() {
+        assert_parses(&[
+            format!("https://{}/bookDetail/{}/", HOST, ID),
+            format!("https://{}/bookDetail/{}//", HOST, ID),
+        ]);
+    }
+
+    #[test]
+    fn book_detail_with_query() {
+        assert_parses(&[
+            format!("https://{}/bookDetail/{}?from=search", HOST, ID),
+            format!("https://{}/bookDetail/{}/?from=search&page=2", HOST, ID),
+        ]);
+    }
+
+    #[test]
+    fn book_detail_with_fragment() {
+        assert_parses(&[
+            format!("https://{}/bookDetail/{}#catalog", HOST, ID),
+            // Routes of the single page app
+            format!("https://{}/#/bookDetail/{}", HOST, ID),
+        ]);
+    }
+
+    #[test]
+    fn reader() {
+        assert_parses(&[
+            format!("https://{}/readBook/{}", HOST, ID),
+            format!("https://{}/reader/{}?page=3", HOST, ID),
+        ]);
+    }
+
+    #[test]
+    fn share_link() {
+        assert_parses(&[
+            format!("https://{}/share?bookId={}", HOST, ID),
+            format!("https://{}/share?bookid={}", HOST, ID),
+            format!("https://{}/#/share?bookId={}&token=x", HOST, ID),
+        ]);
+    }
+
+    #[test]
+    fn bare_id() {
+        assert_parses(&[ID.to_owned(), format!(" {}\n", ID)]);
+    }
+
+    #[test]
+    fn rejected() {
+        for input in [
+            "",
+            "not a book",
+            "https://ereserves.lib.tsinghua.edu.cn/",
+            "https://ereserves.lib.tsinghua.edu.cn/index?token=eyJhb",
+            "https://ereserves.lib.tsinghua.edu.cn/bookDetail/short",
+            "https://example.com/bookDetail/c01e1db11c4041a39db463e810bac8f94af518935a1ec46ef",
+        ] {
+            let e = parse(input).unwrap_err();
+            assert!(matches!(&e, Error::InvalidBookUrl(url) if url == input.trim()));
+            assert_eq!(
+                e.to_string(),
+                format!(
+                    "not a book URL or ID: \"{}\", expected https://{}/bookDetail/<id> or the bare ID",
+                    input, HOST
+                )
+            );
+        }
+    }
+}
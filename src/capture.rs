@@ -0,0 +1,262 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use rand::Rng;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+};
+use tracing::{debug, warn};
+
+/// Requests larger than this are not a token.
+const MAX_REQUEST: usize = 64 * 1024;
+/// Connections that have not sent a whole request by then are dropped, like the idle ones
+/// browsers open in advance.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What the bookmarklet sends from the logged-in ereserves page.
+#[derive(Deserialize)]
+pub struct Captured {
+    pub token: String,
+    /// The page the bookmarklet was clicked on, usually the book detail page.
+    pub url: String,
+}
+
+/// A localhost listener that receives the token from a bookmarklet, so it never has to be
+/// copied by hand.
+///
+/// The bookmarklet posts to a random path, which keeps other pages from feeding the listener
+/// a token of their choice.
+pub struct Capture {
+    listener: TcpListener,
+    secret: Arc<str>,
+}
+
+impl Capture {
+    /// Listens on `port` of the loopback interface, 0 picks a free one.
+    pub async fn bind(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], port))).await?;
+        let secret = rand::rng()
+            .sample_iter(rand::distr::Alphanumeric)
+            .take(24)
+            .map(char::from)
+            .collect::<String>()
+            .into();
+        Ok(Self { listener, secret })
+    }
+
+    /// The `javascript:` URL to save as a bookmark and click on the ereserves page.
+    pub fn bookmarklet(&self) -> io::Result<String> {
+        let endpoint = format!(
+            "http://127.0.0.1:{}/{}",
+            self.listener.local_addr()?.port(),
+            self.secret
+        );
+        Ok(format!(
+            "javascript:(()=>{{const s=[localStorage,sessionStorage];let t=new URLSearchParams(location.search).get('token');for(const x of s){{t=t||x.getItem('token')||x.getItem('Jcclient')}}if(!t){{alert('thubookrs: no token found, please log in first');return}}fetch('{}',{{method:'POST',headers:{{'Content-Type':'text/plain'}},body:JSON.stringify({{token:t,url:location.href}})}}).then(()=>alert('thubookrs: token sent')).catch(e=>alert('thubookrs: '+e))}})()",
+            endpoint
+        ))
+    }
+
+    /// Waits until the bookmarklet sends a token. Each connection is answered on its own, so
+    /// one that sends nothing does not hold up the others.
+    pub async fn wait(&self) -> io::Result<Captured> {
+        let mut connections = JoinSet::new();
+        loop {
+            tokio::select! {
+                accepted = self.listener.accept() => {
+                    let (mut stream, addr) = accepted?;
+                    debug!("Bookmarklet connection from {}", addr);
+                    let secret = Arc::clone(&self.secret);
+                    connections.spawn(async move {
+                        tokio::time::timeout(REQUEST_TIMEOUT, handle(&secret, &mut stream))
+                            .await
+                            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+                    });
+                }
+                Some(joined) = connections.join_next() => match joined {
+                    Ok(Ok(Some(captured))) => return Ok(captured),
+                    Ok(Ok(None)) => {}
+                    Ok(Err(e)) => warn!("Bad request from the bookmarklet: {}", e),
+                    Err(e) => warn!("Bookmarklet connection failed: {}", e),
+                },
+            }
+        }
+    }
+}
+
+/// Answers one request, returning the token once it arrives on the path `secret`.
+async fn handle(secret: &str, stream: &mut TcpStream) -> io::Result<Option<Captured>> {
+    let mut buf = Vec::new();
+    let header_end = loop {
+        if let Some(end) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if buf.len() > MAX_REQUEST || read_some(stream, &mut buf).await? == 0 {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+    };
+    let head = String::from_utf8_lossy(&buf[..header_end]).into_owned();
+    let mut request_line = head.lines().next().unwrap_or("").split(' ');
+    let method = request_line.next().unwrap_or("");
+    let path = request_line.next().unwrap_or("");
+    let content_length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    if path.trim_start_matches('/') != secret {
+        respond(stream, "404 Not Found").await?;
+        return Ok(None);
+    }
+    match method {
+        // The preflight browsers send before a public page may talk to localhost.
+        "OPTIONS" => {
+            respond(stream, "204 No Content").await?;
+            Ok(None)
+        }
+        "POST" if content_length <= MAX_REQUEST => {
+            while buf.len() < header_end + content_length {
+                if read_some(stream, &mut buf).await? == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+            let body = &buf[header_end..header_end + content_length];
+            match serde_json::from_slice::<Captured>(body) {
+                Ok(captured) if !captured.token.is_empty() => {
+                    respond(stream, "200 OK").await?;
+                    Ok(Some(captured))
+                }
+                _ => {
+                    respond(stream, "400 Bad Request").await?;
+                    Err(io::ErrorKind::InvalidData.into())
+                }
+            }
+        }
+        _ => {
+            respond(stream, "405 Method Not Allowed").await?;
+            Ok(None)
+        }
+    }
+}
+
+async fn read_some(stream: &mut TcpStream, buf: &mut Vec<u8>) -> io::Result<usize> {
+    let mut chunk = [0; 4096];
+    let n = stream.read(&mut chunk).await?;
+    buf.extend_from_slice(&chunk[..n]);
+    Ok(n)
+}
+
+async fn respond(stream: &mut TcpStream, status: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\n\
+         Access-Control-Allow-Origin: *\r\n\
+         Access-Control-Allow-Methods: POST, OPTIONS\r\n\
+         Access-Control-Allow-Headers: Content-Type\r\n\
+         Access-Control-Allow-Private-Network: true\r\n\
+         Content-Length: 0\r\n\
+         Connection: close\r\n\r\n",
+        status
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends `request` to `capture` and returns the status line of the response.
+    async fn send(capture: &Capture, request: String) -> String {
+        let mut stream = TcpStream::connect(capture.listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.lines().next().unwrap_or("").to_owned()
+    }
+
+    fn post(path: &str, body: &str) -> String {
+        format!(
+            "POST /{} HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            path,
+            body.len(),
+            body
+        )
+    }
+
+    const BODY: &str =
+        r#"{"token":"eyJ.token","url":"https://ereserves.lib.tsinghua.edu.cn/bookDetail/abc"}"#;
+
+    async fn wait(capture: &Capture) -> Captured {
+        tokio::time::timeout(Duration::from_secs(5), capture.wait())
+            .await
+            .expect("the token did not arrive")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn token_post() {
+        let capture = Capture::bind(0).await.unwrap();
+        let request = post(&capture.secret, BODY);
+        let (captured, status) = tokio::join!(wait(&capture), send(&capture, request));
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(captured.token, "eyJ.token");
+        assert_eq!(
+            captured.url,
+            "https://ereserves.lib.tsinghua.edu.cn/bookDetail/abc"
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_requests() {
+        let capture = Capture::bind(0).await.unwrap();
+        let secret = capture.secret.to_string();
+        let requests = async {
+            [
+                send(&capture, post("wrong-path", BODY)).await,
+                send(&capture, post(&secret, "{\"token\":")).await,
+                send(&capture, post(&secret, r#"{"token":"","url":""}"#)).await,
+                send(&capture, format!("GET /{} HTTP/1.1\r\n\r\n", secret)).await,
+                send(&capture, format!("OPTIONS /{} HTTP/1.1\r\n\r\n", secret)).await,
+                // Closed before the end of the headers
+                send(&capture, "POST / HTTP/1.1\r\nContent-Le".to_owned()).await,
+                send(&capture, post(&secret, BODY)).await,
+            ]
+        };
+        let (captured, statuses) = tokio::join!(wait(&capture), requests);
+        assert_eq!(
+            statuses,
+            [
+                "HTTP/1.1 404 Not Found",
+                "HTTP/1.1 400 Bad Request",
+                "HTTP/1.1 400 Bad Request",
+                "HTTP/1.1 405 Method Not Allowed",
+                "HTTP/1.1 204 No Content",
+                "",
+                "HTTP/1.1 200 OK",
+            ]
+        );
+        assert_eq!(captured.token, "eyJ.token");
+    }
+
+    #[tokio::test]
+    async fn idle_connection() {
+        let capture = Capture::bind(0).await.unwrap();
+        let address = capture.listener.local_addr().unwrap();
+        let request = post(&capture.secret, BODY);
+        let requests = async {
+            // Connected first and never sends anything
+            let idle = TcpStream::connect(address).await.unwrap();
+            let status = send(&capture, request).await;
+            (idle, status)
+        };
+        let (captured, (_idle, status)) = tokio::join!(wait(&capture), requests);
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(captured.token, "eyJ.token");
+    }
+}
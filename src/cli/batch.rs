@@ -0,0 +1,75 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use thubookrs::layout::Job;
+
+use super::table::{pad, truncate, width};
+
+/// Reads a list with one book per line, a URL or book ID optionally followed by the output
+/// name. Blank lines and lines starting with `#` are skipped.
+pub fn read_list(path: &Path) -> io::Result<Vec<Job>> {
+    let text = fs::read_to_string(path)?;
+    Ok(text
+        .lines()
+        .map(|line| line.trim_start_matches('\u{feff}').trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.split_once(char::is_whitespace) {
+            Some((url, name)) => Job {
+                name: Some(name.trim().to_owned()),
+                ..Job::new(url.to_owned())
+            },
+            None => Job::new(line.to_owned()),
+        })
+        .collect())
+}
+
+pub enum Status {
+    Done,
+    Skipped,
+    Failed(String),
+}
+
+/// The outcome of one book, for the summary.
+pub struct Row {
+    pub book: String,
+    pub status: Status,
+    pub output: Option<PathBuf>,
+}
+
+/// Prints one line per book to stdout.
+pub fn print_summary(rows: &[Row]) {
+    let books: Vec<String> = rows.iter().map(|row| truncate(&row.book, 40)).collect();
+    let book_width = books
+        .iter()
+        .map(|book| width(book))
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    println!();
+    println!(
+        "{}  {}  {:<7}  Result",
+        pad("#", 3),
+        pad("Book", book_width),
+        "Status"
+    );
+    for (index, (row, book)) in rows.iter().zip(&books).enumerate() {
+        let (status, result) = match &row.status {
+            Status::Done => ("done", String::new()),
+            Status::Skipped => ("skipped", String::new()),
+            Status::Failed(reason) => ("failed", reason.clone()),
+        };
+        let result = match &row.output {
+            Some(output) if result.is_empty() => output.display().to_string(),
+            _ => result,
+        };
+        println!(
+            "{}  {}  {:<7}  {}",
+            pad(&(index + 1).to_string(), 3),
+            pad(book, book_width),
+            status,
+            result
+        );
+    }
+}
@@ -0,0 +1,352 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::{Arg, ArgAction, ArgMatches, Command, error::ErrorKind, parser::ValueSource};
+use toml::{Table, Value};
+
+const CONFIG_NAME: &str = "config.toml";
+/// Options that cannot be set in the config file: the ones choosing the config itself, the
+/// token, which is saved in the session instead, and the repeatable `-v`.
+const EXCLUDED: [&str; 4] = ["config", "profile", "token", "verbose"];
+
+/// `<config dir>/thubookrs/config.toml`, like `~/.config/thubookrs/config.toml` on Linux.
+pub fn default_path() -> Option<PathBuf> {
+    Some(dirs::config_dir()?.join("thubookrs").join(CONFIG_NAME))
+}
+
+/// The top level settings of the config file at `path` with those of `profile` over them.
+fn load(path: &Path, profile: Option<&str>) -> Result<Table, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("cannot read the config file {}: {}", path.display(), e))?;
+    parse(&text, profile, &path.display().to_string())
+}
+
+/// The top level settings of `text` with those of `profile` over them. `origin` names the
+/// file in the errors.
+fn parse(text: &str, profile: Option<&str>, origin: &str) -> Result<Table, String> {
+    let mut settings: Table = text
+        .parse()
+        .map_err(|e| format!("invalid config file {}: {}", origin, e))?;
+    let profiles = match settings.remove("profile") {
+        None => Table::new(),
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => {
+            return Err(format!(
+                "invalid config file {}: profile must be a table like [profile.archive]",
+                origin
+            ));
+        }
+    };
+    if let Some(profile) = profile {
+        let Some(Value::Table(overrides)) = profiles.get(profile) else {
+            let names: Vec<&str> = profiles.keys().map(String::as_str).collect();
+            return Err(format!(
+                "no profile \"{}\" in {}, the profiles are: {}",
+                profile,
+                origin,
+                names.join(", ")
+            ));
+        };
+        settings.extend(overrides.clone());
+    }
+    Ok(settings)
+}
+
+/// The option named `key` in the config file, by its long name like `2fa` or its id like
+/// `two_factor` or `thread-number`.
+fn find_arg<'a>(command: &'a Command, key: &str) -> Option<&'a Arg> {
+    let id = key.replace('-', "_");
+    command.get_arguments().find(|arg| {
+        !arg.is_positional() && (arg.get_id() == id.as_str() || arg.get_long() == Some(key))
+    })
+}
+
+/// Checks `value` with the value parser of `arg`, by parsing it as the default of a copy.
+fn check(arg: &Arg, value: &str) -> Result<(), String> {
+    let probe = Arg::new("value")
+        .long("value")
+        .action(arg.get_action().clone())
+        .value_parser(arg.get_value_parser().clone())
+        .default_value(value.to_owned());
+    match Command::new("check")
+        .no_binary_name(true)
+        .arg(probe)
+        .try_get_matches_from(Vec::<String>::new())
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            Err(std::error::Error::source(&e)
+                .map_or(String::new(), |source| format!(": {}", source)))
+        }
+    }
+}
+
+/// Parses the command line only for `--config`, `--profile` and the subcommand, ignoring
+/// errors and `--help`, which is shown by the second parse with the defaults from the
+/// config file.
+fn probe(command: &Command) -> ArgMatches {
+    let mut probe = command
+        .clone()
+        .ignore_errors(true)
+        .disable_help_flag(true)
+        .disable_help_subcommand(true)
+        .disable_version_flag(true)
+        .arg(
+            Arg::new("help")
+                .short('h')
+                .long("help")
+                .global(true)
+                .action(ArgAction::SetTrue),
+        );
+    let names: Vec<String> = probe
+        .get_subcommands()
+        .map(|sub| sub.get_name().to_owned())
+        .collect();
+    for name in names {
+        probe = probe.mut_subcommand(name, |sub| sub.disable_help_flag(true));
+    }
+    probe.get_matches()
+}
+
+/// Parses the command line with the settings of the config file as the defaults of the
+/// options, so the built-in defaults are overridden by the config file, which is overridden
+/// by the environment variables and then by the command line. `--help` shows the defaults
+/// from the config file too. Returns the matches and the settings that were used, for the
+/// log.
+pub fn get_matches(mut command: Command) -> (ArgMatches, Vec<String>) {
+    let matches = probe(&command);
+    let explicit = matches.get_one::<PathBuf>("config").cloned();
+    let profile = matches.get_one::<String>("profile").cloned();
+    let Some(path) = explicit.clone().or_else(default_path) else {
+        return (command.get_matches(), Vec::new());
+    };
+    if explicit.is_none() && profile.is_none() && !path.exists() {
+        return (command.get_matches(), Vec::new());
+    }
+    let settings = load(&path, profile.as_deref())
+        .unwrap_or_else(|message| command.error(ErrorKind::Io, message).exit());
+    let (command, used) = apply(command, &matches, &settings, &path.display().to_string())
+        .unwrap_or_else(|e| e.exit());
+    (command.get_matches(), used)
+}
+
+/// Sets `settings` from the config file `origin` as the defaults of the global options of
+/// `command` and of the options of the subcommand in `matches`, leaving out those given on
+/// the command line or in the environment. Returns the command and the settings used.
+fn apply(
+    mut command: Command,
+    matches: &ArgMatches,
+    settings: &Table,
+    origin: &str,
+) -> Result<(Command, Vec<String>), clap::Error> {
+    let subcommand = matches.subcommand();
+    let mut root_defaults = Vec::new();
+    let mut sub_defaults = Vec::new();
+    let mut used = Vec::new();
+    for (key, value) in settings {
+        let invalid = |reason: &str| format!("{} in {}: {}", key, origin, reason);
+        let value = match value {
+            Value::String(value) => value.clone(),
+            Value::Integer(value) => value.to_string(),
+            Value::Float(value) => value.to_string(),
+            Value::Boolean(value) => value.to_string(),
+            _ => {
+                let message = invalid("expected a string, a number or true/false");
+                return Err(command.error(ErrorKind::InvalidValue, message));
+            }
+        };
+        if EXCLUDED.contains(&key.replace('-', "_").as_str()) {
+            let message = invalid("cannot be set in the config file");
+            return Err(command.error(ErrorKind::InvalidValue, message));
+        }
+        // An option of the subcommand, or a global one
+        let (arg, source, defaults) = match subcommand.and_then(|(name, sub_matches)| {
+            let arg = find_arg(command.find_subcommand(name)?, key)?;
+            Some((arg.clone(), sub_matches.value_source(arg.get_id().as_str())))
+        }) {
+            Some((arg, source)) => (arg, source, &mut sub_defaults),
+            None => match find_arg(&command, key) {
+                Some(arg) => {
+                    let source = matches.value_source(arg.get_id().as_str());
+                    (arg.clone(), source, &mut root_defaults)
+                }
+                None => {
+                    // Options like quality apply to the subcommands that have them
+                    if command
+                        .get_subcommands()
+                        .any(|sub| find_arg(sub, key).is_some())
+                    {
+                        continue;
+                    }
+                    let message = invalid("unknown option");
+                    return Err(command.error(ErrorKind::UnknownArgument, message));
+                }
+            },
+        };
+        if matches!(
+            source,
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            continue;
+        }
+        if let Err(reason) = check(&arg, &value) {
+            let message = invalid(&format!("invalid value \"{}\"{}", value, reason));
+            return Err(command.error(ErrorKind::InvalidValue, message));
+        }
+        used.push(format!("{} = {}", key, value));
+        defaults.push((arg.get_id().clone(), value));
+    }
+    for (id, value) in root_defaults {
+        command = command.mut_arg(id, |arg| arg.default_value(value));
+    }
+    if let Some((name, _)) = subcommand {
+        command = command.mut_subcommand(name, |mut sub| {
+            for (id, value) in sub_defaults {
+                sub = sub.mut_arg(id, |arg| arg.default_value(value));
+            }
+            sub
+        });
+    }
+    Ok((command, used))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::value_parser;
+
+    fn command() -> Command {
+        Command::new("thubookrs")
+            .arg(Arg::new("config").global(true).long("config"))
+            .arg(Arg::new("profile").global(true).long("profile"))
+            .arg(
+                Arg::new("rate")
+                    .global(true)
+                    .long("rate")
+                    .value_parser(value_parser!(f64)),
+            )
+            .subcommand(
+                Command::new("download")
+                    .arg(
+                        Arg::new("quality")
+                            .short('q')
+                            .value_parser(value_parser!(i32).range(3..11))
+                            .default_value("10"),
+                    )
+                    .arg(Arg::new("work_dir").long("work-dir"))
+                    .arg(Arg::new("token").long("token")),
+            )
+            .subcommand(
+                Command::new("login").arg(
+                    Arg::new("two_factor")
+                        .long("2fa")
+                        .value_parser(["wechat", "mobile", "totp"])
+                        .default_value("totp"),
+                ),
+            )
+    }
+
+    /// Parses `args` with the config file `text` and its `profile`.
+    fn matches_with(
+        text: &str,
+        profile: Option<&str>,
+        args: &[&str],
+    ) -> Result<(ArgMatches, Vec<String>), clap::Error> {
+        let command = command();
+        let matches = command.clone().try_get_matches_from(args)?;
+        let settings = parse(text, profile, "config.toml").unwrap();
+        let (command, used) = apply(command, &matches, &settings, "config.toml")?;
+        Ok((command.try_get_matches_from(args)?, used))
+    }
+
+    fn quality(matches: &ArgMatches) -> i32 {
+        *matches
+            .subcommand_matches("download")
+            .unwrap()
+            .get_one::<i32>("quality")
+            .unwrap()
+    }
+
+    #[test]
+    fn command_line_over_config() {
+        let text = "quality = 5\nwork-dir = \"books\"\nrate = 2.0";
+        let (matches, used) = matches_with(text, None, &["thubookrs", "download"]).unwrap();
+        assert_eq!(quality(&matches), 5);
+        assert_eq!(*matches.get_one::<f64>("rate").unwrap(), 2.0);
+        assert_eq!(used.len(), 3);
+
+        let args = ["thubookrs", "--rate", "1", "download", "-q", "7"];
+        let (matches, used) = matches_with(text, None, &args).unwrap();
+        assert_eq!(quality(&matches), 7);
+        assert_eq!(*matches.get_one::<f64>("rate").unwrap(), 1.0);
+        assert_eq!(used, ["work-dir = books"]);
+    }
+
+    #[test]
+    fn profile_over_top_level() {
+        let text = "quality = 5\n[profile.archive]\nquality = 10\n[profile.draft]\nquality = 3";
+        let args = ["thubookrs", "download"];
+        let (matches, _) = matches_with(text, Some("archive"), &args).unwrap();
+        assert_eq!(quality(&matches), 10);
+        let (matches, _) = matches_with(text, None, &args).unwrap();
+        assert_eq!(quality(&matches), 5);
+        let e = parse(text, Some("fast"), "config.toml").unwrap_err();
+        assert_eq!(
+            e,
+            "no profile \"fast\" in config.toml, the profiles are: archive, draft"
+        );
+    }
+
+    #[test]
+    fn long_names_and_ids() {
+        for text in ["2fa = \"wechat\"", "two_factor = \"wechat\""] {
+            let (matches, _) = matches_with(text, None, &["thubookrs", "login"]).unwrap();
+            let login = matches.subcommand_matches("login").unwrap();
+            assert_eq!(
+                login.get_one::<String>("two_factor").unwrap(),
+                "wechat",
+                "{}",
+                text
+            );
+        }
+        // Options of other subcommands are left for them
+        let (matches, used) =
+            matches_with("2fa = \"mobile\"", None, &["thubookrs", "download"]).unwrap();
+        assert_eq!(quality(&matches), 10);
+        assert!(used.is_empty());
+    }
+
+    #[test]
+    fn invalid_value() {
+        let args = ["thubookrs", "download"];
+        for text in ["quality = 2", "quality = \"high\"", "quality = [5]"] {
+            let e = matches_with(text, None, &args).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidValue, "{}", text);
+            assert!(e.to_string().contains("quality in config.toml"), "{}", e);
+        }
+        // Not checked when the command line overrides it
+        let e = matches_with("quality = 2", None, &["thubookrs", "download", "-q", "5"]);
+        assert!(e.is_ok());
+    }
+
+    #[test]
+    fn unknown_and_excluded_keys() {
+        let args = ["thubookrs", "download"];
+        let e = matches_with("colour = \"gray\"", None, &args).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnknownArgument);
+        assert!(
+            e.to_string()
+                .contains("colour in config.toml: unknown option"),
+            "{}",
+            e
+        );
+        let e = matches_with("token = \"abc\"", None, &args).unwrap_err();
+        assert!(
+            e.to_string().contains("cannot be set in the config file"),
+            "{}",
+            e
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use thubookrs::pre_process::{BookSummary, DownloadTask, Shelf};
+
+use super::table::{pad, truncate, width};
+
+/// Prints the search results as a table, with the ID to pass to `info` or `download`.
+pub fn print_search(results: &[BookSummary]) {
+    if results.is_empty() {
+        println!("No books found");
+        return;
+    }
+    let rows: Vec<[String; 4]> = results
+        .iter()
+        .map(|result| {
+            [
+                result.book_id.clone(),
+                truncate(&result.title, 40),
+                truncate(&result.authors.join("; "), 24),
+                truncate(&result.publisher, 24),
+            ]
+        })
+        .collect();
+    let header = ["ID", "Title", "Authors", "Publisher"];
+    let widths: Vec<usize> = (0..header.len())
+        .map(|column| {
+            rows.iter()
+                .map(|row| width(&row[column]))
+                .chain([width(header[column])])
+                .max()
+                .unwrap()
+        })
+        .collect();
+    for row in [header.map(str::to_owned)].iter().chain(&rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, &columns)| pad(cell, columns))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+/// Prints each list with its books.
+pub fn print_shelves(shelves: &[Shelf]) {
+    for shelf in shelves {
+        println!("{} ({} books)", shelf.name, shelf.books.len());
+        if !shelf.books.is_empty() {
+            print_search(&shelf.books);
+        }
+        println!();
+    }
+}
+
+/// Prints the details of a parsed book and its table of contents.
+pub fn print_book(task: &DownloadTask) {
+    let detail = &task.detail;
+    let pages: usize = task
+        .chapters
+        .iter()
+        .map(|chapter| chapter.page_urls.len())
+        .sum();
+    println!("Title:     {}", detail.title);
+    println!("Authors:   {}", detail.authors.join("; "));
+    println!("Publisher: {}", detail.publisher);
+    println!("ISBN:      {}", detail.isbn);
+    println!("Published: {}", detail.publish_date);
+    println!("ID:        {}", detail.book_id);
+    println!("Pages:     {}", pages);
+    println!("Chapters:");
+    let number_width = task.chapters.len().to_string().len();
+    // Numbered as --chapters and --pages expect them
+    for (chap_num, chapter) in task.chapters.iter().enumerate() {
+        println!(
+            "{:>number_width$}  {}{} ({} pages)",
+            chap_num + 1,
+            "  ".repeat(chapter.level as usize),
+            chapter.title,
+            chapter.page_urls.len()
+        );
+    }
+}
@@ -0,0 +1,68 @@
+use std::io::{self, IsTerminal, Write};
+
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{filter::Targets, fmt::MakeWriter, prelude::*};
+
+use super::progress::Bars;
+
+/// Writes each log line in one go while the progress bars are hidden.
+struct BarWriter {
+    bars: Bars,
+    buf: Vec<u8>,
+}
+
+impl Write for BarWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for BarWriter {
+    fn drop(&mut self) {
+        self.bars.suspend(|| io::stderr().write_all(&self.buf)).ok();
+    }
+}
+
+struct MakeBarWriter(Bars);
+
+impl<'a> MakeWriter<'a> for MakeBarWriter {
+    type Writer = BarWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        BarWriter {
+            bars: self.0.clone(),
+            buf: Vec::new(),
+        }
+    }
+}
+
+/// Installs the global logger writing to stderr. Only this crate logs at `level`, the HTTP
+/// stack below it is limited to warnings.
+pub fn init(level: LevelFilter, json: bool, bars: &Bars) {
+    let filter = Targets::new()
+        .with_target(env!("CARGO_CRATE_NAME"), level)
+        .with_default(LevelFilter::WARN.min(level));
+    let writer = MakeBarWriter(bars.clone());
+    if json {
+        tracing_subscriber::registry()
+            .with(tracing_subscriber::fmt::layer().json().with_writer(writer))
+            .with(filter)
+            .init();
+    } else {
+        tracing_subscriber::registry()
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(io::stderr().is_terminal())
+                    .without_time()
+                    .with_target(false)
+                    .with_writer(writer),
+            )
+            .with(filter)
+            .init();
+    }
+}
@@ -0,0 +1,8 @@
+pub mod batch;
+pub mod config;
+pub mod info;
+pub mod logging;
+pub mod progress;
+pub mod prompt;
+pub mod table;
+pub mod token;
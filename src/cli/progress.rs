@@ -0,0 +1,98 @@
+use std::{
+    collections::HashMap,
+    io::IsTerminal,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use thubookrs::progress::{Event, Progress, Stage};
+use tracing::{debug, info};
+
+struct Bar {
+    bar: ProgressBar,
+    bytes: u64,
+}
+
+/// Draws the progress events as bars when stdout is a terminal, and falls back to one log
+/// line per step otherwise, so piped output stays readable.
+#[derive(Clone)]
+pub struct Bars {
+    multi: MultiProgress,
+    interactive: bool,
+    bars: Arc<Mutex<HashMap<Stage, Bar>>>,
+}
+
+impl Bars {
+    /// Bars are only drawn when `enabled` and stdout is a terminal.
+    pub fn new(enabled: bool) -> Self {
+        let interactive = enabled && std::io::stdout().is_terminal();
+        let target = if interactive {
+            ProgressDrawTarget::stdout()
+        } else {
+            ProgressDrawTarget::hidden()
+        };
+        Self {
+            multi: MultiProgress::with_draw_target(target),
+            interactive,
+            bars: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Hides the bars while `f` runs, so it can write to the terminal without garbling them.
+    pub fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.multi.suspend(f)
+    }
+
+    /// The listener to hand to the library.
+    pub fn progress(&self) -> Progress {
+        let bars = self.clone();
+        Progress::new(move |event| bars.handle(event))
+    }
+
+    fn handle(&self, event: &Event) {
+        let mut bars = self.bars.lock().unwrap();
+        match event {
+            Event::Started { stage, total } => {
+                let bar = self.multi.add(ProgressBar::new(*total));
+                bar.set_style(
+                    ProgressStyle::with_template(&format!(
+                        "{{prefix:>12}} [{{bar:30}}] {{pos}}/{{len}} {} {{msg}} ({{elapsed}}, ETA {{eta}})",
+                        stage.unit()
+                    ))
+                    .unwrap()
+                    .progress_chars("=> "),
+                );
+                bar.set_prefix(stage.name());
+                bar.enable_steady_tick(Duration::from_millis(200));
+                bars.insert(*stage, Bar { bar, bytes: 0 });
+            }
+            Event::Step { stage, message } => {
+                if let Some(bar) = bars.get(stage) {
+                    bar.bar.inc(1);
+                }
+                if self.interactive {
+                    debug!("{}", message);
+                } else {
+                    info!("{}", message);
+                }
+            }
+            Event::Bytes { stage, bytes } => {
+                if let Some(bar) = bars.get_mut(stage) {
+                    bar.bytes += bytes;
+                    let seconds = bar.bar.elapsed().as_secs_f64().max(0.001);
+                    bar.bar.set_message(format!(
+                        "{}, {}/s",
+                        HumanBytes(bar.bytes),
+                        HumanBytes((bar.bytes as f64 / seconds) as u64)
+                    ));
+                }
+            }
+            Event::Finished { stage } => {
+                if let Some(bar) = bars.remove(stage) {
+                    bar.bar.finish();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use std::io::{self, Write};
+
+use thubookrs::login::{Credentials, TwoFactor};
+
+fn read_line(prompt: &str) -> io::Result<String> {
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+    let mut line = String::new();
+    if io::stdin().read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(line.trim().to_owned())
+}
+
+/// Asks for what is missing of the username and the password. The password is never echoed.
+pub fn credentials(username: Option<&str>, two_factor: TwoFactor) -> io::Result<Credentials> {
+    let username = match username {
+        Some(username) => username.to_owned(),
+        None => read_line("Username: ")?,
+    };
+    let password = rpassword::prompt_password("Password: ")?;
+    Ok(Credentials {
+        username,
+        password,
+        two_factor,
+    })
+}
+
+pub fn code(two_factor: TwoFactor) -> io::Result<String> {
+    read_line(match two_factor {
+        TwoFactor::WeChat => "Verification code sent via WeChat: ",
+        TwoFactor::Mobile => "Verification code sent via SMS: ",
+        TwoFactor::Totp => "Verification code from the authenticator app: ",
+    })
+}
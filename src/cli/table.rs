@@ -0,0 +1,34 @@
+/// Terminal columns taken by `s`, counting CJK characters as two.
+pub fn width(s: &str) -> usize {
+    s.chars()
+        .map(|c| match c as u32 {
+            0x1100..=0x115F
+            | 0x2E80..=0xA4CF
+            | 0xAC00..=0xD7A3
+            | 0xF900..=0xFAFF
+            | 0xFE30..=0xFE4F
+            | 0xFF00..=0xFF60
+            | 0xFFE0..=0xFFE6 => 2,
+            _ => 1,
+        })
+        .sum()
+}
+
+/// Cuts `s` to at most `max` columns.
+pub fn truncate(s: &str, max: usize) -> String {
+    if width(s) <= max {
+        return s.to_owned();
+    }
+    let mut out = String::new();
+    for c in s.chars() {
+        if width(&out) + width(c.encode_utf8(&mut [0; 4])) > max - 3 {
+            break;
+        }
+        out.push(c);
+    }
+    out + "..."
+}
+
+pub fn pad(s: &str, columns: usize) -> String {
+    format!("{}{}", s, " ".repeat(columns.saturating_sub(width(s))))
+}
@@ -0,0 +1,69 @@
+use thubookrs::{error::Error, session::Session};
+use tracing::{debug, warn};
+
+#[derive(PartialEq, Eq)]
+pub enum Source {
+    /// `--token` or `THUBOOK_TOKEN`.
+    Argument,
+    /// The login or the bookmarklet of this run.
+    Fresh,
+    /// The session saved by an earlier run.
+    Saved,
+}
+
+pub struct Token {
+    pub value: String,
+    pub source: Source,
+}
+
+impl Token {
+    pub fn new(value: String, source: Source) -> Self {
+        Self { value, source }
+    }
+
+    /// The token saved by an earlier run, unless it is known to have expired.
+    pub fn saved() -> Result<Self, Error> {
+        let session = Session::load().ok_or(Error::MissingToken)?;
+        if session.is_expired() {
+            forget();
+            return Err(Error::InvalidToken(
+                "the saved token is past its expiry time".to_owned(),
+            ));
+        }
+        debug!("Using the saved token");
+        Ok(Self::new(session.token, Source::Saved))
+    }
+
+    /// Remembers the token if `result` shows it works, forgets it if it was rejected.
+    pub fn check<T>(&self, result: Result<T, Error>) -> Result<T, Error> {
+        match &result {
+            Ok(_) => self.remember(),
+            Err(Error::InvalidToken(_)) => self.reject(),
+            Err(_) => {}
+        }
+        result
+    }
+
+    /// Saves a token that just worked for the next runs.
+    pub fn remember(&self) {
+        if self.source == Source::Saved {
+            return;
+        }
+        if let Err(e) = Session::new(&self.value).save() {
+            warn!("Failed to save the token: {}", e);
+        }
+    }
+
+    /// Forgets the saved token once the server rejected it.
+    fn reject(&self) {
+        if self.source == Source::Saved {
+            forget();
+        }
+    }
+}
+
+fn forget() {
+    if let Err(e) = Session::clear() {
+        warn!("Failed to remove the saved token: {}", e);
+    }
+}
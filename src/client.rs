@@ -0,0 +1,174 @@
+use std::{io, path::Path, sync::Arc, time::Duration};
+
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    convert::{self, Bookmark, ConvertOptions, Metadata},
+    download::{Downloader, Failure, RetryPolicy},
+    error::Error,
+    login::{Authenticator, Credentials, TwoFactor},
+    pre_process::{BookSummary, DownloadTask, Preprocessor, Shelf},
+    progress::{Event, Progress},
+    selection::Selection,
+    throttle::Throttle,
+};
+
+/// Parses, downloads and converts books with one shared configuration.
+///
+/// Logging in, parsing and downloading share a single [`Throttle`], so the configured rate
+/// applies to every request the client sends.
+pub struct Client {
+    authenticator: Authenticator,
+    preprocessor: Preprocessor,
+    downloader: Downloader,
+    concurrency: usize,
+    progress: Progress,
+}
+
+/// Configures a [`Client`], see [`Client::builder`].
+pub struct ClientBuilder {
+    retry: RetryPolicy,
+    requests_per_second: Option<f64>,
+    jitter: Duration,
+    concurrency: usize,
+    progress: Progress,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self {
+            retry: RetryPolicy::default(),
+            requests_per_second: None,
+            jitter: Duration::ZERO,
+            concurrency: 4,
+            progress: Progress::default(),
+        }
+    }
+}
+
+impl ClientBuilder {
+    /// How failed pages are retried.
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// The maximum number of requests per second. Unlimited by default.
+    pub fn requests_per_second(mut self, requests_per_second: f64) -> Self {
+        self.requests_per_second = Some(requests_per_second);
+        self
+    }
+
+    /// Delays each request by a random duration up to `jitter`.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// The number of simultaneous downloads, 4 by default.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Where progress events are reported. Discarded by default.
+    pub fn progress(mut self, progress: Progress) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Reports progress events to `callback`.
+    pub fn on_progress(self, callback: impl Fn(&Event) + Send + Sync + 'static) -> Self {
+        self.progress(Progress::new(callback))
+    }
+
+    pub fn build(self) -> Result<Client, Error> {
+        let throttle = Arc::new(Throttle::new(self.requests_per_second, self.jitter));
+        Ok(Client {
+            authenticator: Authenticator::new(Arc::clone(&throttle))?,
+            preprocessor: Preprocessor::new(Arc::clone(&throttle))?,
+            downloader: Downloader::new(self.retry, throttle)?,
+            concurrency: self.concurrency,
+            progress: self.progress,
+        })
+    }
+}
+
+impl Client {
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::default()
+    }
+
+    pub fn progress(&self) -> &Progress {
+        &self.progress
+    }
+
+    /// Logs in through the unified authentication and returns the token for [`Client::parse`].
+    /// `code` is asked for the verification code of the second factor.
+    pub async fn login(
+        &self,
+        credentials: &Credentials,
+        code: impl Fn(TwoFactor) -> io::Result<String>,
+    ) -> Result<String, Error> {
+        self.authenticator.login(credentials, code).await
+    }
+
+    /// Searches the books available to the user by title, author or ISBN.
+    pub async fn search(&self, query: &str, token: &str) -> Result<Vec<BookSummary>, Error> {
+        self.preprocessor.search(query, token).await
+    }
+
+    /// The user's bookshelf and course reserves.
+    pub async fn shelves(&self, token: &str) -> Result<Vec<Shelf>, Error> {
+        self.preprocessor.shelves(token).await
+    }
+
+    /// Looks up the book at `url` and lists the pages of all its chapters.
+    pub async fn parse(&self, url: &str, token: &str) -> Result<DownloadTask, Error> {
+        self.preprocessor.parse(url, token, &self.progress).await
+    }
+
+    /// Downloads every selected page of `task` that is not in `save_dir` yet and returns the
+    /// pages that failed. Pending downloads stop once `cancel` is cancelled.
+    pub async fn download(
+        &self,
+        task: DownloadTask,
+        save_dir: &Path,
+        selection: &Selection,
+        cancel: CancellationToken,
+    ) -> Result<Vec<Failure>, Error> {
+        self.downloader
+            .download_imgs(
+                task,
+                save_dir,
+                selection,
+                self.concurrency,
+                cancel,
+                &self.progress,
+            )
+            .await
+    }
+
+    /// Converts the images in `dir` to a PDF at `pdf_path`, stopping once `cancel` is
+    /// cancelled.
+    pub async fn convert(
+        &self,
+        dir: &Path,
+        pdf_path: &Path,
+        options: &ConvertOptions,
+        bookmarks: &[Bookmark],
+        metadata: &Metadata,
+        cancel: &CancellationToken,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        convert::convert(
+            dir,
+            pdf_path,
+            options,
+            bookmarks,
+            metadata,
+            &self.progress,
+            cancel,
+        )
+        .await
+    }
+}
@@ -1,81 +1,420 @@
-use image::ImageReader;
-use lopdf::content::Content;
-use lopdf::{Document, Object, SaveOptions, Stream, dictionary};
+use image::codecs::pnm::{PnmEncoder, PnmSubtype, SampleEncoding};
+use image::{DynamicImage, ExtendedColorType, ImageDecoder, ImageFormat, ImageReader};
+use lopdf::content::{Content, Operation};
+use lopdf::{Dictionary, Object, ObjectId, Stream, dictionary, text_string};
 
 use std::collections::HashMap;
 use std::{
-    cmp::Ordering,
-    fs,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
     path::{Path, PathBuf},
+    sync::Arc,
 };
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
 
-fn get_images(dir: &Path) -> Vec<PathBuf> {
+use crate::{
+    bilevel::{self, PageKind},
+    error::Error,
+    pdf::PdfWriter,
+    progress::{Progress, Stage},
+    selection::Selection,
+};
+
+/// The directory in the book directory where the resized images are kept until the PDF is
+/// complete.
+const INTERMEDIATE_NAME: &str = "intermediate";
+
+/// How the images are turned into pages.
+#[derive(Clone)]
+pub struct ConvertOptions {
+    /// The scale of the pages in tenths of the original size, from 3 to 10.
+    pub quality: i32,
+    /// Resize all pages to the most common page size.
+    pub auto_resize: bool,
+    /// The pages to convert, all downloaded pages by default.
+    pub selection: Selection,
+    /// Whether the pages are kept in color or turned gray or black and white.
+    pub color_mode: ColorMode,
+}
+
+/// How the colors of the pages are stored. Gray pages are a third of the size of color ones,
+/// and black and white pages, compressed with CCITT Group 4, a fraction of that.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Pages without color are gray, and text pages without shades black and white.
+    Auto,
+    /// All pages as they are.
+    #[default]
+    Color,
+    Gray,
+    /// Black and white, with the threshold following the local brightness. For text only,
+    /// pictures lose their shades.
+    Bw,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        Self {
+            quality: 10,
+            auto_resize: false,
+            selection: Selection::default(),
+            color_mode: ColorMode::default(),
+        }
+    }
+}
+
+/// An entry of the PDF outline. The n-th bookmark points to the first page of chapter n.
+#[derive(Clone)]
+pub struct Bookmark {
+    pub title: String,
+    pub level: u32,
+    /// The number of pages of the chapter, to number the pages as in the book.
+    pub pages: usize,
+}
+
+/// Bibliographic data written to the document information dictionary and the XMP stream.
+#[derive(Clone, Default)]
+pub struct Metadata {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub publisher: String,
+    pub isbn: String,
+    pub publish_date: String,
+}
+
+struct OutlineNode {
+    title: String,
+    page_id: ObjectId,
+    children: Vec<OutlineNode>,
+}
+
+/// Parses the `<chapter>_<page>` numbers out of an image name, `None` for any other file.
+fn parse_page_name(img_path: &Path) -> Option<(usize, usize)> {
+    let stem = img_path.file_stem()?.to_str()?;
+    let (chap_num, page_num) = stem.split_once('_')?;
+    Some((chap_num.parse().ok()?, page_num.parse().ok()?))
+}
+
+/// The `<chapter>_<page>` images in `dir` in reading order, an error if there are none.
+fn get_images(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let entries = fs::read_dir(dir).map_err(|source| Error::ImageDir {
+        dir: dir.to_path_buf(),
+        source,
+    })?;
     let mut result = Vec::new();
-    for entry in
-        fs::read_dir(dir).unwrap_or_else(|_| panic!("Can't open directory {}", dir.display()))
-    {
+    for entry in entries {
         if let Ok(entry) = entry
             && let Ok(filetype) = entry.file_type()
             && filetype.is_file()
+            && let Some(numbers) = parse_page_name(&entry.path())
         {
-            result.push(entry.file_name());
-        }
-    }
-    result.sort_by(|a, b| {
-        let a = a.to_str().unwrap();
-        let b = b.to_str().unwrap();
-        let mut iter_a = a.split('_');
-        let mut iter_b = b.split('_');
-        let a_chap: i32 = iter_a.next().unwrap().parse().unwrap();
-        let b_chap: i32 = iter_b.next().unwrap().parse().unwrap();
-        let order = a_chap.cmp(&b_chap);
-        match order {
-            Ordering::Equal => {
-                let a_page: i32 = iter_a
-                    .next()
-                    .unwrap()
-                    .split('.')
-                    .next()
-                    .unwrap()
-                    .parse()
-                    .unwrap();
-                let b_page: i32 = iter_b
-                    .next()
-                    .unwrap()
-                    .split('.')
-                    .next()
-                    .unwrap()
-                    .parse()
-                    .unwrap();
-                a_page.cmp(&b_page)
+            result.push((numbers, entry.path()));
+        }
+    }
+    if result.is_empty() {
+        return Err(Error::NoImages(dir.to_path_buf()));
+    }
+    result.sort_by_key(|(numbers, _)| *numbers);
+    Ok(result.into_iter().map(|(_, path)| path).collect())
+}
+
+fn get_chapter_num(img_path: &Path) -> usize {
+    parse_page_name(img_path).unwrap().0
+}
+
+/// The page of the book each image is, counted from 0: from the page counts of the chapters
+/// if known, from the order of the images otherwise.
+fn get_book_indexes(imgs: &[PathBuf], bookmarks: &[Bookmark]) -> Vec<usize> {
+    let offsets: Vec<usize> = bookmarks
+        .iter()
+        .scan(0, |offset, bookmark| {
+            let start = *offset;
+            *offset += bookmark.pages;
+            Some(start)
+        })
+        .collect();
+    imgs.iter()
+        .enumerate()
+        .map(|(position, img_path)| {
+            let (chap_num, page_num) = parse_page_name(img_path).unwrap();
+            offsets
+                .get(chap_num)
+                .map_or(position, |offset| offset + page_num)
+        })
+        .collect()
+}
+
+/// Numbers the pages as in the book, starting a new range of labels wherever pages were
+/// left out.
+fn build_page_labels(book_indexes: &[usize]) -> Dictionary {
+    let mut nums: Vec<Object> = Vec::new();
+    for (position, index) in book_indexes.iter().enumerate() {
+        if position == 0 || book_indexes[position - 1] + 1 != *index {
+            nums.push((position as i64).into());
+            nums.push(
+                dictionary! {
+                    "S" => "D",
+                    "St" => *index as i64 + 1,
+                }
+                .into(),
+            );
+        }
+    }
+    dictionary! { "Nums" => nums }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn build_info(metadata: &Metadata) -> Dictionary {
+    let mut info = dictionary! {
+        "Producer" => text_string(concat!("thubookrs ", env!("CARGO_PKG_VERSION"))),
+    };
+    if !metadata.title.is_empty() {
+        info.set("Title", text_string(&metadata.title));
+    }
+    if !metadata.authors.is_empty() {
+        info.set("Author", text_string(&metadata.authors.join("; ")));
+    }
+    if !metadata.publisher.is_empty() {
+        info.set("Publisher", text_string(&metadata.publisher));
+    }
+    if !metadata.isbn.is_empty() {
+        info.set("ISBN", text_string(&metadata.isbn));
+    }
+    info
+}
+
+fn build_xmp(metadata: &Metadata) -> String {
+    let mut dc = String::new();
+    if !metadata.title.is_empty() {
+        dc += &format!(
+            "<dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:title>",
+            escape_xml(&metadata.title)
+        );
+    }
+    if !metadata.authors.is_empty() {
+        let authors: String = metadata
+            .authors
+            .iter()
+            .map(|author| format!("<rdf:li>{}</rdf:li>", escape_xml(author)))
+            .collect();
+        dc += &format!("<dc:creator><rdf:Seq>{}</rdf:Seq></dc:creator>", authors);
+    }
+    if !metadata.publisher.is_empty() {
+        dc += &format!(
+            "<dc:publisher><rdf:Bag><rdf:li>{}</rdf:li></rdf:Bag></dc:publisher>",
+            escape_xml(&metadata.publisher)
+        );
+    }
+    if !metadata.publish_date.is_empty() {
+        dc += &format!(
+            "<dc:date><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:date>",
+            escape_xml(&metadata.publish_date)
+        );
+    }
+    if !metadata.isbn.is_empty() {
+        dc += &format!(
+            "<dc:identifier>urn:isbn:{}</dc:identifier><prism:isbn>{}</prism:isbn>",
+            escape_xml(&metadata.isbn),
+            escape_xml(&metadata.isbn)
+        );
+    }
+    format!(
+        concat!(
+            "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n",
+            "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">",
+            "<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">",
+            "<rdf:Description rdf:about=\"\" ",
+            "xmlns:dc=\"http://purl.org/dc/elements/1.1/\" ",
+            "xmlns:prism=\"http://prismstandard.org/namespaces/basic/2.0/\" ",
+            "xmlns:pdf=\"http://ns.adobe.com/pdf/1.3/\">",
+            "{}<pdf:Producer>thubookrs {}</pdf:Producer>",
+            "</rdf:Description></rdf:RDF></x:xmpmeta>\n",
+            "<?xpacket end=\"w\"?>"
+        ),
+        dc,
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+fn build_outline_tree(
+    bookmarks: &[Bookmark],
+    imgs: &[PathBuf],
+    page_ids: &[ObjectId],
+) -> Vec<OutlineNode> {
+    let chapter_nums: Vec<usize> = imgs.iter().map(|img| get_chapter_num(img)).collect();
+    // (level, node) pairs whose children may still grow
+    let mut stack: Vec<(u32, OutlineNode)> = Vec::new();
+    let mut roots = Vec::new();
+    for (chap_num, bookmark) in bookmarks.iter().enumerate() {
+        // A chapter without pages of its own points to the next page that exists
+        let Some(page_index) = chapter_nums.iter().position(|num| *num >= chap_num) else {
+            break;
+        };
+        // Chapters left out of the selection are left out of the outline, but the ones
+        // without pages of their own stay as parents of the chapters that follow them
+        if bookmarks[chap_num..chapter_nums[page_index]]
+            .iter()
+            .any(|bookmark| bookmark.pages > 0)
+        {
+            continue;
+        }
+        let node = OutlineNode {
+            title: bookmark.title.clone(),
+            page_id: page_ids[page_index],
+            children: Vec::new(),
+        };
+        while let Some((level, _)) = stack.last()
+            && *level >= bookmark.level
+        {
+            let (_, finished) = stack.pop().unwrap();
+            match stack.last_mut() {
+                Some((_, parent)) => parent.children.push(finished),
+                None => roots.push(finished),
             }
-            _ => order,
         }
-    });
-    result.iter().map(|filename| dir.join(filename)).collect()
+        stack.push((bookmark.level, node));
+    }
+    while let Some((_, finished)) = stack.pop() {
+        match stack.last_mut() {
+            Some((_, parent)) => parent.children.push(finished),
+            None => roots.push(finished),
+        }
+    }
+    roots
+}
+
+/// Writes `nodes` as siblings under `parent_id` and returns the ids of the first and last
+/// item together with the number of visible descendants.
+fn add_outline_items<W: Write>(
+    pdf: &mut PdfWriter<W>,
+    nodes: Vec<OutlineNode>,
+    parent_id: ObjectId,
+) -> io::Result<(ObjectId, ObjectId, i64)> {
+    let ids: Vec<ObjectId> = nodes.iter().map(|_| pdf.reserve()).collect();
+    let mut count = ids.len() as i64;
+    for (index, node) in nodes.into_iter().enumerate() {
+        let mut item = dictionary! {
+            "Title" => text_string(&node.title),
+            "Parent" => parent_id,
+            "Dest" => vec![node.page_id.into(), "Fit".into()],
+        };
+        if index > 0 {
+            item.set("Prev", ids[index - 1]);
+        }
+        if index + 1 < ids.len() {
+            item.set("Next", ids[index + 1]);
+        }
+        if !node.children.is_empty() {
+            let (first, last, descendants) = add_outline_items(pdf, node.children, ids[index])?;
+            item.set("First", first);
+            item.set("Last", last);
+            item.set("Count", descendants);
+            count += descendants;
+        }
+        pdf.write(ids[index], &Object::Dictionary(item))?;
+    }
+    Ok((ids[0], *ids.last().unwrap(), count))
+}
+
+type TaskError = Box<dyn std::error::Error + Send + Sync>;
+
+fn interrupted() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "Keyboard interrupted")
+}
+
+/// Whether the image can be embedded as it is when its size does not change. JPEGs are
+/// embedded byte for byte with the DCTDecode filter, unless they are CMYK, which the PDF
+/// would show with inverted or wrong colors. Other formats are decoded losslessly anyway.
+fn embeds_unchanged(img_path: &Path) -> bool {
+    let Ok(reader) = ImageReader::open(img_path).and_then(|reader| reader.with_guessed_format())
+    else {
+        return false;
+    };
+    match reader.format() {
+        Some(ImageFormat::Jpeg) => reader.into_decoder().is_ok_and(|decoder| {
+            matches!(
+                decoder.original_color_type(),
+                ExtendedColorType::L8 | ExtendedColorType::Rgb8
+            )
+        }),
+        Some(_) => true,
+        None => false,
+    }
+}
+
+/// The name a processed copy of `img_path` is cached under, with the size and the modification
+/// time of the image, so a copy of an image that has changed since is not reused.
+fn cache_name(img_path: &Path) -> io::Result<String> {
+    let metadata = fs::metadata(img_path)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis());
+    Ok(format!(
+        "{}-{}-{}.{}",
+        img_path.file_stem().unwrap_or_default().to_string_lossy(),
+        metadata.len(),
+        modified,
+        img_path.extension().unwrap_or_default().to_string_lossy()
+    ))
+}
+
+/// Writes `path` through `write` to a temporary file renamed once complete, so an interrupted
+/// run never leaves a truncated image that the next run takes as done.
+fn save_atomically(
+    path: &Path,
+    write: impl FnOnce(&Path) -> Result<(), TaskError>,
+) -> Result<(), TaskError> {
+    let tmp_path = path.with_file_name(format!(
+        ".{}.tmp",
+        path.file_name().unwrap().to_string_lossy()
+    ));
+    if let Err(e) = write(&tmp_path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
 }
 
+/// Resizes and converts the images of `book` into `intermediate_dir` as needed, and returns
+/// the directory of the copies and the file to embed for each image: the original if it is
+/// unchanged, otherwise the processed copy. The copies go to a subdirectory per book, quality,
+/// color mode and common size, so those of a run with other options are not reused.
 async fn pre_process_imgs(
-    imgs: &Vec<PathBuf>,
+    imgs: &[PathBuf],
     intermediate_dir: &Path,
-    quality: i32,
-    auto_resize: bool,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let runtime = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .unwrap();
+    book: &str,
+    options: &ConvertOptions,
+    progress: &Progress,
+    cancel: &CancellationToken,
+) -> Result<(PathBuf, Vec<PathBuf>), TaskError> {
+    let quality = options.quality;
+    // Decoding and resizing is CPU bound, so run as many at once as there are cores
+    let parallelism = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let semaphore = Arc::new(Semaphore::new(parallelism));
     let total = imgs.len();
-    let mut handles: Vec<
-        tokio::task::JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>>,
-    > = Vec::with_capacity(total);
+    let mut handles: Vec<(usize, tokio::task::JoinHandle<Result<PathBuf, TaskError>>)> =
+        Vec::with_capacity(total);
     let mut common_size = None;
-    if auto_resize {
+    let mut sizes = Vec::with_capacity(total);
+    if options.auto_resize {
         let mut size_count = HashMap::new();
+        let phase = progress.phase(Stage::Measure, total as u64);
         for img_path in imgs {
-            let img = ImageReader::open(img_path)?.decode()?;
-            let width = img.width();
-            let height = img.height();
+            // Only the header is read, not the whole image
+            let (width, height) = ImageReader::open(img_path)?
+                .with_guessed_format()?
+                .into_dimensions()?;
+            sizes.push((width, height));
             match size_count.get_mut(&(width, height)) {
                 None => {
                     size_count.insert((width, height), 1);
@@ -84,131 +423,598 @@ async fn pre_process_imgs(
                     *count += 1;
                 }
             }
+            phase.step(&format!("Measure complete: {}", img_path.display()));
         }
+        phase.finish();
         common_size = size_count
             .drain()
             .max_by_key(|(_, count)| *count)
             .map(|(size, _)| size);
     }
     if let Some((width, height)) = common_size {
-        println!("Auto resizing with width: {width}, height: {height}");
+        info!("Auto resizing with width: {width}, height: {height}");
     }
-    for img_path in imgs {
+    let mode = match options.color_mode {
+        ColorMode::Auto => "auto",
+        ColorMode::Color => "color",
+        ColorMode::Gray => "gray",
+        ColorMode::Bw => "bw",
+    };
+    let mut key = format!("{}-q{}-{}", book, quality, mode);
+    if let Some((width, height)) = common_size {
+        key += &format!("-{}x{}", width, height);
+    }
+    let intermediate_dir = intermediate_dir.join(key);
+    fs::create_dir_all(&intermediate_dir)?;
+    let phase = progress.phase(Stage::Resize, total as u64);
+    let mut sources = Vec::with_capacity(total);
+    for (index, img_path) in imgs.iter().enumerate() {
+        let file_name = img_path.file_name().unwrap();
+        let keeps_size =
+            quality == 10 && common_size.is_none_or(|size| sizes.get(index) == Some(&size));
+        if keeps_size && options.color_mode == ColorMode::Color && embeds_unchanged(img_path) {
+            phase.step(&format!("Resize not needed: {}", file_name.display()));
+            sources.push(img_path.clone());
+            continue;
+        }
+        // In auto mode, the color pages are found while processing
+        let embeds_color =
+            keeps_size && options.color_mode == ColorMode::Auto && embeds_unchanged(img_path);
+        let output_path = intermediate_dir.join(cache_name(img_path)?);
+        // Black and white pages are kept as 1 bit PBM until they are encoded into the PDF
+        let bilevel_path = output_path.with_extension("pbm");
+        sources.push(output_path.clone());
         let img_path_clone = img_path.clone();
-        let intermediate_dir_clone = intermediate_dir.to_path_buf();
-        let handle = runtime.spawn_blocking(move || {
-            let file_name = img_path_clone.file_name().unwrap();
-            let output_path = intermediate_dir_clone.join(file_name);
-            if output_path.exists() {
-                println!("Resize already completed: {}, skip", file_name.display());
-                return Ok(());
+        let color_mode = options.color_mode;
+        let permit = tokio::select! {
+            permit = Arc::clone(&semaphore).acquire_owned() => permit?,
+            _ = cancel.cancelled() => break,
+        };
+        let phase = phase.clone();
+        let cancel = cancel.clone();
+        let handle = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            if cancel.is_cancelled() {
+                return Err(interrupted().into());
+            }
+            let file_name = img_path_clone.file_name().unwrap().to_owned();
+            let done = [
+                (color_mode != ColorMode::Bw).then_some(&output_path),
+                matches!(color_mode, ColorMode::Auto | ColorMode::Bw).then_some(&bilevel_path),
+            ];
+            if let Some(done) = done.into_iter().flatten().find(|path| path.exists()) {
+                phase.step(&format!(
+                    "Resize already completed: {}, skip",
+                    file_name.display()
+                ));
+                return Ok(done.clone());
+            }
+            let img = ImageReader::open(&img_path_clone)?
+                .with_guessed_format()?
+                .decode()?;
+            let mut kind = match color_mode {
+                ColorMode::Auto => None,
+                ColorMode::Color => Some(PageKind::Color),
+                ColorMode::Gray => Some(PageKind::Gray),
+                ColorMode::Bw => Some(PageKind::Bilevel),
+            };
+            if embeds_color {
+                match bilevel::classify(&img) {
+                    PageKind::Color => {
+                        phase.step(&format!("Resize not needed: {}", file_name.display()));
+                        return Ok(img_path_clone);
+                    }
+                    page_kind => kind = Some(page_kind),
+                }
             }
-            let img = ImageReader::open(&img_path_clone)?.decode()?;
-            let (width, height) = if let Some((width, height)) = common_size {
-                (width, height)
+            let img = if keeps_size {
+                img
             } else {
-                (img.width(), img.height())
+                let (width, height) = common_size.unwrap_or((img.width(), img.height()));
+                let scale = |length: u32| (length * quality as u32 + 5) / 10;
+                img.resize(
+                    scale(width),
+                    scale(height),
+                    image::imageops::FilterType::Lanczos3,
+                )
             };
 
-            let img = img.resize(
-                width / 10 * quality as u32,
-                height / 10 * quality as u32,
-                image::imageops::FilterType::Lanczos3,
-            );
-
-            let output_path = intermediate_dir_clone.join(file_name);
-            img.save(&output_path)?;
-            println!("Resize complete: {}", file_name.display());
-            Ok(())
+            let kind = kind.unwrap_or_else(|| bilevel::classify(&img));
+            let output_path = match kind {
+                PageKind::Color | PageKind::Gray => {
+                    let img = match kind {
+                        PageKind::Gray => DynamicImage::ImageLuma8(img.to_luma8()),
+                        _ => img,
+                    };
+                    let format = ImageFormat::from_path(&output_path)?;
+                    save_atomically(&output_path, |path| Ok(img.save_with_format(path, format)?))?;
+                    output_path
+                }
+                PageKind::Bilevel => {
+                    let mut bits = bilevel::binarize(&img.to_luma8());
+                    // PBM samples are 0 for black and 1 for white
+                    bits.pixels_mut().for_each(|pixel| pixel.0[0] /= 255);
+                    save_atomically(&bilevel_path, |path| {
+                        let encoder = PnmEncoder::new(BufWriter::new(File::create(path)?))
+                            .with_subtype(PnmSubtype::Bitmap(SampleEncoding::Binary));
+                        Ok(DynamicImage::ImageLuma8(bits).write_with_encoder(encoder)?)
+                    })?;
+                    bilevel_path
+                }
+            };
+            phase.step(&format!("Resize complete: {}", file_name.display()));
+            Ok(output_path)
         });
-        handles.push(handle);
+        handles.push((index, handle));
     }
-    for handle in handles {
-        let _ = handle.await;
+    for (index, handle) in handles {
+        let result = handle.await?;
+        if cancel.is_cancelled() {
+            return Err(interrupted().into());
+        }
+        sources[index] = result?;
     }
-    runtime.shutdown_background();
-    Ok(())
+    if cancel.is_cancelled() {
+        return Err(interrupted().into());
+    }
+    phase.finish();
+    Ok((intermediate_dir, sources))
 }
 
-async fn img2pdf(
-    imgs: Vec<PathBuf>,
-    pdf_path: &Path,
-    quality: i32,
-    auto_resize: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let intermediate_dir = pdf_path.parent().unwrap().join("intermediate");
-    if !intermediate_dir.exists() {
-        fs::create_dir_all(&intermediate_dir)?;
+/// A black and white page compressed with CCITT Group 4, or deflated if it is too wide for it.
+fn bilevel_xobject(path: &Path) -> Result<Stream, TaskError> {
+    let bits = ImageReader::open(path)?.decode()?.into_luma8();
+    let mut dict = dictionary! {
+        "Type" => "XObject",
+        "Subtype" => "Image",
+        "Width" => bits.width(),
+        "Height" => bits.height(),
+        "ColorSpace" => "DeviceGray",
+        "BitsPerComponent" => 1,
+    };
+    if let Some(data) = bilevel::encode_g4(&bits) {
+        dict.set("Filter", "CCITTFaxDecode");
+        dict.set(
+            "DecodeParms",
+            dictionary! {
+                "K" => -1,
+                "Columns" => bits.width(),
+                "Rows" => bits.height(),
+            },
+        );
+        return Ok(Stream::new(dict, data));
     }
-    if let Err(e) = pre_process_imgs(&imgs, &intermediate_dir, quality, auto_resize).await {
-        println!("Convert failed: {}", e);
+    // Rows of 1 bit samples, each starting on a byte, with 1 for white
+    let mut data = Vec::with_capacity(bits.height() as usize * bits.width().div_ceil(8) as usize);
+    for row in bits.rows() {
+        let row: Vec<_> = row.collect();
+        for pixels in row.chunks(8) {
+            let byte = pixels.iter().enumerate().fold(0u8, |byte, (index, pixel)| {
+                byte | (u8::from(pixel.0[0] >= 128) << (7 - index))
+            });
+            data.push(byte);
+        }
     }
+    let mut stream = Stream::new(dict, data);
+    stream.compress()?;
+    Ok(stream)
+}
 
-    let mut doc = Document::with_version("2.0");
-    let pages_id = doc.new_object_id();
-    let catalog_id = doc.add_object(dictionary! {
-        "Type" => "Catalog",
-        "Pages" => pages_id,
-    });
-    doc.trailer.set("Root", catalog_id);
-    let total = imgs.len();
-    let mut page_objects = Vec::with_capacity(total);
-    for (index, img_path) in imgs.iter().enumerate() {
-        let img_path = intermediate_dir.join(img_path.file_name().unwrap());
-        let image_xobject = lopdf::xobject::image(&img_path)?;
-        let content = Content { operations: vec![] };
-        let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
-        let width = image_xobject.dict.get(b"Width").unwrap().as_i64().unwrap();
-        let height = image_xobject.dict.get(b"Height").unwrap().as_i64().unwrap();
-        let page_id = doc.add_object(dictionary! {
+/// Writes the pages one after another, so only one image is in memory at a time, followed
+/// by the page tree, the outline and the metadata. `pages` are the images with the file to
+/// embed for each.
+fn write_pdf(
+    pages: &[(PathBuf, PathBuf)],
+    book_indexes: &[usize],
+    path: &Path,
+    bookmarks: &[Bookmark],
+    metadata: &Metadata,
+    progress: &Progress,
+    cancel: &CancellationToken,
+) -> Result<(), TaskError> {
+    let mut pdf = PdfWriter::new(BufWriter::new(File::create(path)?), "2.0")?;
+    let pages_id = pdf.reserve();
+    let total = pages.len();
+    let mut page_ids = Vec::with_capacity(total);
+    let phase = progress.phase(Stage::Assemble, total as u64);
+    for (index, (_, img_path)) in pages.iter().enumerate() {
+        if cancel.is_cancelled() {
+            return Err(interrupted().into());
+        }
+        let image_xobject = if img_path.extension().is_some_and(|ext| ext == "pbm") {
+            bilevel_xobject(img_path)?
+        } else {
+            let mut image_xobject = lopdf::xobject::image(img_path)?;
+            // JPEGs are embedded as they are with the DCTDecode filter, raw pixels are deflated
+            image_xobject.compress()?;
+            image_xobject
+        };
+        let width = image_xobject.dict.get(b"Width")?.as_i64()?;
+        let height = image_xobject.dict.get(b"Height")?.as_i64()?;
+        let image_id = pdf.add(&Object::Stream(image_xobject))?;
+        let content = Content {
+            operations: vec![
+                Operation::new("q", vec![]),
+                Operation::new(
+                    "cm",
+                    vec![
+                        width.into(),
+                        0.into(),
+                        0.into(),
+                        height.into(),
+                        0.into(),
+                        0.into(),
+                    ],
+                ),
+                Operation::new("Do", vec![Object::Name(b"Im0".to_vec())]),
+                Operation::new("Q", vec![]),
+            ],
+        };
+        let content_id = pdf.add(&Object::Stream(Stream::new(
+            dictionary! {},
+            content.encode()?,
+        )))?;
+        let page_id = pdf.add(&Object::Dictionary(dictionary! {
             "Type" => "Page",
             "Parent" => pages_id,
             "Contents" => content_id,
             "MediaBox" => vec![0.into(), 0.into(), width.into(), height.into()],
-        });
-        doc.insert_image(
-            page_id,
-            image_xobject,
-            (0 as f32, 0 as f32),
-            (width as f32, height as f32),
-        )?;
-        page_objects.push(page_id.into());
-        println!("Convert complete: {}/{total}", index + 1)
+            "Resources" => dictionary! {
+                "XObject" => dictionary! { "Im0" => image_id },
+            },
+        }))?;
+        page_ids.push(page_id);
+        phase.step(&format!("Convert complete: {}/{total}", index + 1));
     }
+    phase.finish();
 
-    let count = page_objects.len();
-    let pages = dictionary! {
-        "Type" => "Pages",
-        "Kids" => page_objects,
-        "Count" => count as i32,
-    };
-
-    doc.objects.insert(pages_id, Object::Dictionary(pages));
-
-    fs::remove_dir_all(&intermediate_dir)?;
-    doc.compress();
-    let mut file = std::fs::File::create(pdf_path)?;
-    doc.save_with_options(
-        &mut file,
-        SaveOptions::builder()
-            .linearize(true)
-            .use_object_streams(true)
-            .use_xref_streams(true)
-            .compression_level(9)
-            .max_objects_per_stream(count + 2)
-            .build(),
+    pdf.write(
+        pages_id,
+        &Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Kids" => page_ids.iter().map(|id| Object::from(*id)).collect::<Vec<_>>(),
+            "Count" => page_ids.len() as i64,
+        }),
     )?;
-    //doc.save_modern(&mut file)?;
+    let xmp = Stream::new(
+        dictionary! {
+            "Type" => "Metadata",
+            "Subtype" => "XML",
+        },
+        build_xmp(metadata).into_bytes(),
+    );
+    // XMP is meant to be readable by tools that do not parse PDF, so it is not compressed
+    let metadata_id = pdf.add(&Object::Stream(xmp))?;
+    let info_id = pdf.add(&Object::Dictionary(build_info(metadata)))?;
+    let mut catalog = dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+        "Metadata" => metadata_id,
+        "PageLabels" => build_page_labels(book_indexes),
+    };
+    let imgs: Vec<PathBuf> = pages.iter().map(|(img_path, _)| img_path.clone()).collect();
+    let outline_tree = build_outline_tree(bookmarks, &imgs, &page_ids);
+    if !outline_tree.is_empty() {
+        let outlines_id = pdf.reserve();
+        let (first, last, count) = add_outline_items(&mut pdf, outline_tree, outlines_id)?;
+        pdf.write(
+            outlines_id,
+            &Object::Dictionary(dictionary! {
+                "Type" => "Outlines",
+                "First" => first,
+                "Last" => last,
+                "Count" => count,
+            }),
+        )?;
+        catalog.set("Outlines", outlines_id);
+        catalog.set("PageMode", "UseOutlines");
+    }
+    let catalog_id = pdf.add(&Object::Dictionary(catalog))?;
+    pdf.finish(dictionary! {
+        "Root" => catalog_id,
+        "Info" => info_id,
+    })?;
     Ok(())
 }
 
+/// Converts the `<chapter>_<page>` images in `dir` that are part of the selection to a PDF at
+/// `pdf_path`. Stops with an [`io::ErrorKind::Interrupted`] error once `cancel` is cancelled,
+/// keeping the processed images in `dir` for the next run.
 pub async fn convert(
     dir: &Path,
     pdf_path: &Path,
-    quality: i32,
-    auto_resize: bool,
+    options: &ConvertOptions,
+    bookmarks: &[Bookmark],
+    metadata: &Metadata,
+    progress: &Progress,
+    cancel: &CancellationToken,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let imgs = get_images(dir);
-    img2pdf(imgs, pdf_path, quality, auto_resize).await?;
+    let imgs = get_images(dir)?;
+    let book_indexes = get_book_indexes(&imgs, bookmarks);
+    let (imgs, book_indexes): (Vec<PathBuf>, Vec<usize>) = imgs
+        .into_iter()
+        .zip(book_indexes)
+        .filter(|(img_path, index)| {
+            let (chap_num, page_num) = parse_page_name(img_path).unwrap();
+            options.selection.contains(chap_num, page_num, *index)
+        })
+        .unzip();
+    if imgs.is_empty() && !options.selection.is_all() {
+        return Err(Error::EmptySelection.into());
+    }
+    // Next to the images, so conversions of other books never see them
+    let intermediate_dir = dir.join(INTERMEDIATE_NAME);
+    let book = dir
+        .canonicalize()?
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    let (work_dir, sources) =
+        pre_process_imgs(&imgs, &intermediate_dir, &book, options, progress, cancel)
+            .await
+            .map_err(|e| e as Box<dyn std::error::Error>)?;
+
+    // Renamed once complete, so an interrupted run never leaves a truncated PDF behind
+    let tmp_path = pdf_path.with_file_name(format!(
+        ".{}.tmp",
+        pdf_path.file_name().unwrap().to_string_lossy()
+    ));
+    // Encoding and writing the pages is CPU and IO bound, so keep it off the runtime
+    let written = {
+        let tmp_path = tmp_path.clone();
+        let bookmarks = bookmarks.to_vec();
+        let metadata = metadata.clone();
+        let progress = progress.clone();
+        let cancel = cancel.clone();
+        let pages: Vec<(PathBuf, PathBuf)> = imgs.into_iter().zip(sources).collect();
+        tokio::task::spawn_blocking(move || {
+            write_pdf(
+                &pages,
+                &book_indexes,
+                &tmp_path,
+                &bookmarks,
+                &metadata,
+                &progress,
+                &cancel,
+            )
+        })
+        .await?
+    };
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    fs::rename(&tmp_path, pdf_path)?;
+    fs::remove_dir_all(&work_dir)?;
+    // Kept while other conversions of the book use it
+    let _ = fs::remove_dir(&intermediate_dir);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The chapters of a book: a part without pages of its own, two chapters, then another
+    /// part with one chapter.
+    fn bookmarks() -> Vec<Bookmark> {
+        [
+            ("Part I", 1, 0),
+            ("Ch1", 2, 3),
+            ("Ch2", 2, 2),
+            ("Part II", 1, 0),
+            ("Ch3", 2, 2),
+        ]
+        .into_iter()
+        .map(|(title, level, pages)| Bookmark {
+            title: title.to_owned(),
+            level,
+            pages,
+        })
+        .collect()
+    }
+
+    fn images(names: &[&str]) -> Vec<PathBuf> {
+        names.iter().map(PathBuf::from).collect()
+    }
+
+    fn outline_titles(nodes: &[OutlineNode]) -> Vec<String> {
+        nodes
+            .iter()
+            .map(|node| {
+                let children = outline_titles(&node.children);
+                if children.is_empty() {
+                    format!("{}@{}", node.title, node.page_id.0)
+                } else {
+                    format!(
+                        "{}@{} [{}]",
+                        node.title,
+                        node.page_id.0,
+                        children.join(", ")
+                    )
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn images_in_reading_order() {
+        let dir = std::env::temp_dir().join(format!("thubookrs-images-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let missing = get_images(&dir.join("missing"));
+        let empty = get_images(&dir);
+        for name in [
+            "2_0.jpg",
+            "1_10.jpg",
+            "1_2.png",
+            "manifest.json",
+            "cover.jpg",
+        ] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        let imgs = get_images(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(missing, Err(Error::ImageDir { .. })));
+        let Err(Error::NoImages(path)) = empty else {
+            panic!("an empty directory has images")
+        };
+        assert_eq!(path, dir);
+        let names: Vec<_> = imgs
+            .unwrap()
+            .iter()
+            .map(|img| img.file_name().unwrap().to_owned())
+            .collect();
+        assert_eq!(names, ["1_2.png", "1_10.jpg", "2_0.jpg"]);
+    }
+
+    /// A 401 by 300 page of black bars, saved as `name` in `dir`.
+    fn save_page(dir: &Path, name: &str, shade: u8) -> PathBuf {
+        let path = dir.join(name);
+        image::RgbImage::from_fn(401, 300, |x, y| {
+            let ink = x % 12 < 4 && y % 30 < 12;
+            image::Rgb([if ink { shade } else { 255 }; 3])
+        })
+        .save(&path)
+        .unwrap();
+        path
+    }
+
+    async fn process(imgs: &[PathBuf], dir: &Path, quality: i32, color_mode: ColorMode) -> PathBuf {
+        let options = ConvertOptions {
+            quality,
+            color_mode,
+            ..Default::default()
+        };
+        let (_, sources) = pre_process_imgs(
+            imgs,
+            &dir.join(INTERMEDIATE_NAME),
+            "book",
+            &options,
+            &Progress::default(),
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        sources.into_iter().next().unwrap()
+    }
+
+    #[tokio::test]
+    async fn full_quality_keeps_the_size() {
+        let dir = std::env::temp_dir().join(format!("thubookrs-resize-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let imgs = [save_page(&dir, "1_0.png", 0)];
+        let mut sizes = Vec::new();
+        for (quality, color_mode) in [
+            (10, ColorMode::Bw),
+            (10, ColorMode::Gray),
+            (10, ColorMode::Auto),
+            (5, ColorMode::Bw),
+        ] {
+            let source = process(&imgs, &dir, quality, color_mode).await;
+            sizes.push(image::image_dimensions(&source).unwrap());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(sizes[..3], [(401, 300); 3]);
+        let (width, height) = sizes[3];
+        assert!((200..=201).contains(&width), "{}", width);
+        assert_eq!(height, 150);
+    }
+
+    #[tokio::test]
+    async fn processed_copies_follow_the_images() {
+        let dir = std::env::temp_dir().join(format!("thubookrs-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let imgs = [save_page(&dir, "1_0.png", 0)];
+        let first = process(&imgs, &dir, 10, ColorMode::Bw).await;
+        let again = process(&imgs, &dir, 10, ColorMode::Bw).await;
+        let gray = process(&imgs, &dir, 10, ColorMode::Gray).await;
+        // Downloaded again, or replaced by the page of another book
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        save_page(&dir, "1_0.png", 100);
+        let replaced = process(&imgs, &dir, 10, ColorMode::Bw).await;
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(first.starts_with(dir.join(INTERMEDIATE_NAME).join("book-q10-bw")));
+        assert_eq!(again, first);
+        assert_ne!(gray, first);
+        assert_ne!(replaced, first);
+    }
+
+    #[test]
+    fn book_indexes_follow_the_chapters() {
+        let imgs = images(&["1_1.jpg", "1_2.jpg", "4_0.jpg"]);
+        assert_eq!(get_book_indexes(&imgs, &bookmarks()), [1, 2, 5]);
+        // Without a manifest, the images are numbered in order
+        assert_eq!(get_book_indexes(&imgs, &[]), [0, 1, 2]);
+    }
+
+    #[test]
+    fn page_labels_restart_after_a_gap() {
+        let labels = build_page_labels(&[1, 2, 5, 6]);
+        let nums = labels.get(b"Nums").unwrap().as_array().unwrap();
+        assert_eq!(nums.len(), 4);
+        assert_eq!(nums[0].as_i64().unwrap(), 0);
+        assert_eq!(
+            nums[1]
+                .as_dict()
+                .unwrap()
+                .get(b"St")
+                .unwrap()
+                .as_i64()
+                .unwrap(),
+            2
+        );
+        assert_eq!(nums[2].as_i64().unwrap(), 2);
+        assert_eq!(
+            nums[3]
+                .as_dict()
+                .unwrap()
+                .get(b"St")
+                .unwrap()
+                .as_i64()
+                .unwrap(),
+            6
+        );
+
+        let labels = build_page_labels(&[0, 1, 2]);
+        let nums = labels.get(b"Nums").unwrap().as_array().unwrap();
+        assert_eq!(nums.len(), 2);
+        assert_eq!(
+            nums[1]
+                .as_dict()
+                .unwrap()
+                .get(b"St")
+                .unwrap()
+                .as_i64()
+                .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn outline_of_the_whole_book() {
+        let imgs = images(&[
+            "1_0.jpg", "1_1.jpg", "1_2.jpg", "2_0.jpg", "2_1.jpg", "4_0.jpg",
+        ]);
+        let page_ids: Vec<ObjectId> = (0..imgs.len() as u32).map(|n| (n, 0)).collect();
+        let outline = build_outline_tree(&bookmarks(), &imgs, &page_ids);
+        assert_eq!(
+            outline_titles(&outline),
+            ["Part I@0 [Ch1@0, Ch2@3]", "Part II@5 [Ch3@5]"]
+        );
+    }
+
+    #[test]
+    fn outline_skips_chapters_left_out() {
+        // Chapters 1 and 3 only
+        let imgs = images(&["1_0.jpg", "1_1.jpg", "1_2.jpg", "4_0.jpg", "4_1.jpg"]);
+        let page_ids: Vec<ObjectId> = (0..imgs.len() as u32).map(|n| (n, 0)).collect();
+        let outline = build_outline_tree(&bookmarks(), &imgs, &page_ids);
+        assert_eq!(
+            outline_titles(&outline),
+            ["Part I@0 [Ch1@0]", "Part II@3 [Ch3@3]"]
+        );
+    }
+}
@@ -1,15 +1,24 @@
 use std::{
-    fs,
+    fmt, fs,
     io::Write,
     path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 
 use rand::Rng;
-use reqwest::{Client, ClientBuilder, header};
+use reqwest::{Client, ClientBuilder, StatusCode, header};
 use reqwest_cookie_store::{CookieStore, CookieStoreMutex};
+use tokio::sync::Semaphore;
+use tracing::{debug, info, warn};
 
-use crate::pre_process::DownloadTask;
+use crate::{
+    error::Error,
+    pre_process::DownloadTask,
+    progress::{Phase, Progress, Stage},
+    selection::Selection,
+    throttle::Throttle,
+};
 
 fn get_tmp_name() -> PathBuf {
     let rand_string: String = rand::rng()
@@ -20,14 +29,168 @@ fn get_tmp_name() -> PathBuf {
     PathBuf::from(format!(".tmp{}", rand_string))
 }
 
+/// The name an image is saved under, `<chapter>_<page>.<ext>` with both numbers counted from 0.
+pub fn get_file_name(chap_num: usize, page_num: usize, img_path: &str) -> String {
+    format!(
+        "{}_{}.{}",
+        chap_num,
+        page_num,
+        img_path
+            .split('/')
+            .next_back()
+            .unwrap()
+            .split('.')
+            .next_back()
+            .unwrap()
+    )
+}
+
+/// How often and how patiently a page is requested again after a transient error.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with full jitter, the delay before retry number `attempt` (from 0).
+    fn delay(&self, attempt: u32) -> Duration {
+        self.ceiling(attempt)
+            .mul_f64(rand::rng().random_range(0.0..=1.0))
+    }
+
+    /// The longest delay before retry number `attempt`, doubling up to `max_delay`.
+    fn ceiling(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay)
+    }
+}
+
+#[derive(Debug)]
+struct PageError {
+    message: String,
+    retryable: bool,
+}
+
+impl fmt::Display for PageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl From<reqwest::Error> for PageError {
+    fn from(e: reqwest::Error) -> Self {
+        let retryable = e.is_timeout()
+            || e.is_connect()
+            || e.is_request()
+            || e.is_body()
+            || e.status().is_some_and(is_retryable_status);
+        Self {
+            message: e.to_string(),
+            retryable,
+        }
+    }
+}
+
+impl From<std::io::Error> for PageError {
+    fn from(e: std::io::Error) -> Self {
+        Self {
+            message: e.to_string(),
+            retryable: false,
+        }
+    }
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status.is_server_error()
+}
+
+/// Rejects error statuses, retrying only those that may go away.
+fn check_status(status: StatusCode) -> Result<(), PageError> {
+    if status.is_success() {
+        return Ok(());
+    }
+    Err(PageError {
+        message: format!("HTTP status {}", status),
+        retryable: is_retryable_status(status),
+    })
+}
+
+/// Rejects anything that is not an image, like the HTML pages served for an expired session.
+/// These are retryable, as the server tends to return them under load as well.
+fn check_image(content_type: Option<&str>, bytes: &[u8]) -> Result<(), PageError> {
+    if let Some(content_type) = content_type
+        && !content_type.starts_with("image/")
+        && !content_type.starts_with("application/octet-stream")
+    {
+        return Err(PageError {
+            message: format!("unexpected content type {}", content_type),
+            retryable: true,
+        });
+    }
+    let complete = match image::guess_format(bytes) {
+        // A connection closed early cuts off the end marker
+        Ok(image::ImageFormat::Jpeg) => bytes
+            .iter()
+            .rposition(|&byte| byte != 0)
+            .is_some_and(|end| bytes[..=end].ends_with(&[0xFF, 0xD9])),
+        Ok(image::ImageFormat::Png) => bytes.ends_with(b"IEND\xAE\x42\x60\x82"),
+        Ok(
+            image::ImageFormat::Gif
+            | image::ImageFormat::Bmp
+            | image::ImageFormat::Tiff
+            | image::ImageFormat::WebP,
+        ) => true,
+        _ => {
+            return Err(PageError {
+                message: format!("response of {} bytes is not an image", bytes.len()),
+                retryable: true,
+            });
+        }
+    };
+    if !complete {
+        return Err(PageError {
+            message: format!("image of {} bytes is truncated", bytes.len()),
+            retryable: true,
+        });
+    }
+    Ok(())
+}
+
+/// A page that could not be downloaded even after retrying.
+pub struct Failure {
+    pub file_name: String,
+    pub reason: String,
+}
+
 #[derive(Clone)]
 pub struct Downloader {
     client: Client,
+    retry: RetryPolicy,
+    throttle: Arc<Throttle>,
     //cookie_store: Arc<CookieStoreMutex>,
 }
 
 impl Downloader {
-    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn new(retry: RetryPolicy, throttle: Arc<Throttle>) -> Result<Self, reqwest::Error> {
         let mut default_headers = header::HeaderMap::new();
         let cookie_store = Arc::new(CookieStoreMutex::new(CookieStore::default()));
         default_headers.insert("User-Agent", header::HeaderValue::from_static("Mozilla/5.0 (Windows NT 6.1; WOW64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/39.0.2171.71 Safari/537.36"));
@@ -35,7 +198,11 @@ impl Downloader {
             client: ClientBuilder::new()
                 .default_headers(default_headers)
                 .cookie_provider(Arc::clone(&cookie_store))
+                .connect_timeout(retry.connect_timeout)
+                .read_timeout(retry.read_timeout)
                 .build()?,
+            retry,
+            throttle,
             //cookie_store,
         })
     }
@@ -46,95 +213,259 @@ impl Downloader {
         img_path: &str,
         save_dir: &Path,
         filename: &str,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        phase: &Phase,
+    ) -> Result<(), PageError> {
         let url = "https://ereserves.lib.tsinghua.edu.cn/readkernel/JPGFile/DownJPGJsNetPage";
         let save_path = save_dir.join(filename);
+        self.throttle.wait().await;
         let res = self
             .client
             .get(url)
             .query(&[("filePath", img_path)])
             .header("Cookie", format!("BotuReadKernel={}", botu_read_kernel))
-            .send();
-        println!("Start Downloading: {}", &filename);
+            .send()
+            .await?;
+        let status = res.status();
+        debug!("{} {} for {}", status, url, filename);
+        self.throttle.observe(status);
+        check_status(status)?;
+        let content_type = res
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+        let bytes = res.bytes().await?;
+        phase.add_bytes(bytes.len() as u64);
+        check_image(content_type.as_deref(), &bytes)?;
         let tmp_name = loop {
-            let tmp_name = get_tmp_name();
+            let tmp_name = save_dir.join(get_tmp_name());
             if !tmp_name.exists() {
                 break tmp_name;
             }
         };
-        let bytes = res.await?.bytes().await?;
-        let mut file = fs::File::create(&tmp_name)?;
-        file.write_all(&bytes)?;
-        fs::rename(tmp_name, save_path)?;
-        println!("Download success: {}", filename);
+        let written = fs::File::create(&tmp_name)
+            .and_then(|mut file| file.write_all(&bytes))
+            .and_then(|_| fs::rename(&tmp_name, save_path));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp_name);
+            return Err(e.into());
+        }
+        phase.step(&format!("Download success: {}", filename));
         Ok(())
     }
 
+    async fn download_with_retry(
+        &self,
+        botu_read_kernel: &str,
+        img_path: &str,
+        save_dir: &Path,
+        filename: &str,
+        phase: &Phase,
+    ) -> Result<(), PageError> {
+        let mut attempt = 0;
+        loop {
+            match self
+                .download_one(botu_read_kernel, img_path, save_dir, filename, phase)
+                .await
+            {
+                Err(e) if e.retryable && attempt < self.retry.retries => {
+                    let delay = self.retry.delay(attempt);
+                    attempt += 1;
+                    warn!(
+                        "Download failed: {}, retry {}/{} in {:.1}s: {}",
+                        filename,
+                        attempt,
+                        self.retry.retries,
+                        delay.as_secs_f64(),
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Downloads every selected page that is not on disk yet and returns the pages that failed.
+    /// Only fails as a whole if `save_dir` cannot be created.
     pub async fn download_imgs(
         &self,
         task: DownloadTask,
         save_dir: &Path,
-        thread_num: usize,
+        selection: &Selection,
+        concurrency: usize,
         cancel: tokio_util::sync::CancellationToken,
-    ) -> bool {
-        if !save_dir.exists() {
-            fs::create_dir_all(save_dir).unwrap();
-        }
-        let runtime = tokio::runtime::Builder::new_multi_thread()
-            .worker_threads(thread_num)
-            .enable_all()
-            .build()
-            .unwrap();
-        let mut download_names = Vec::new();
-        let mut handles = Vec::new();
-        for (chap_num, img_urls) in task.page_urls.iter().enumerate() {
-            for (page_num, img_path) in img_urls.iter().enumerate() {
-                let filename = format!(
-                    "{}_{}.{}",
-                    chap_num,
-                    page_num,
-                    img_path
-                        .split('/')
-                        .next_back()
-                        .unwrap()
-                        .split('.')
-                        .next_back()
-                        .unwrap()
-                );
-                let path = save_dir.join(&filename);
-                if path.exists() {
-                    println!("Already downloaded: {}, skip", &filename);
+        progress: &Progress,
+    ) -> Result<Vec<Failure>, Error> {
+        fs::create_dir_all(save_dir).map_err(|source| Error::DownloadDir {
+            dir: save_dir.to_path_buf(),
+            source,
+        })?;
+        let mut pending = Vec::new();
+        let mut skipped = 0;
+        let mut index = 0;
+        for (chap_num, chapter) in task.chapters.iter().enumerate() {
+            for (page_num, img_path) in chapter.page_urls.iter().enumerate() {
+                index += 1;
+                if !selection.contains(chap_num, page_num, index - 1) {
                     continue;
                 }
-                download_names.push(filename.clone());
-                let botu_read_kernel = task.botu_read_kernel.clone();
-                let img_path = img_path.clone();
-                let save_dir = save_dir.to_path_buf();
-                let self_clone = self.clone();
-                let cancel = cancel.clone();
-                let handle = runtime.spawn(async move {
-                    tokio::select! {
-                        result = self_clone
-                        .download_one(&botu_read_kernel, &img_path, &save_dir, &filename)
-                        => { result }
-                        _ = cancel.cancelled() => { Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "Keyboard interrupted").into()) }
-                    }
-                });
-                handles.push(handle);
+                let filename = get_file_name(chap_num, page_num, img_path);
+                if save_dir.join(&filename).exists() {
+                    skipped += 1;
+                } else {
+                    pending.push((filename, img_path));
+                }
             }
         }
+        if skipped > 0 {
+            info!("Already downloaded: {} pages, skip", skipped);
+        }
+        let phase = progress.phase(Stage::Download, pending.len() as u64);
+        // Each task holds a permit for its whole lifetime, including the backoff between
+        // retries, so at most `concurrency` requests are in flight at any time.
+        let semaphore = Arc::new(Semaphore::new(concurrency));
+        let mut handles = Vec::new();
+        for (filename, img_path) in pending {
+            let permit = tokio::select! {
+                permit = Arc::clone(&semaphore).acquire_owned() => permit.unwrap(),
+                _ = cancel.cancelled() => break,
+            };
+            let botu_read_kernel = task.botu_read_kernel.clone();
+            let img_path = img_path.to_owned();
+            let save_dir = save_dir.to_path_buf();
+            let self_clone = self.clone();
+            let cancel = cancel.clone();
+            let filename_clone = filename.clone();
+            let phase = phase.clone();
+            let handle = tokio::spawn(async move {
+                let _permit = permit;
+                tokio::select! {
+                    result = self_clone
+                    .download_with_retry(&botu_read_kernel, &img_path, &save_dir, &filename_clone, &phase)
+                    => { result }
+                    _ = cancel.cancelled() => { Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "Keyboard interrupted").into()) }
+                }
+            });
+            handles.push((filename, handle));
+        }
 
-        let mut success = true;
-        for handle in handles {
-            let result = handle.await;
-            if let Ok(result) = result
-                && let Err(e) = result
-            {
-                println!("{}", e);
-                success = false;
+        let mut failures = Vec::new();
+        for (file_name, handle) in handles {
+            let reason = match handle.await {
+                Ok(Ok(())) => continue,
+                Ok(Err(e)) => e.to_string(),
+                Err(e) => e.to_string(),
+            };
+            failures.push(Failure { file_name, reason });
+        }
+        phase.finish();
+        Ok(failures)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{ImageFormat, RgbImage};
+
+    use super::*;
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let mut bytes = Cursor::new(Vec::new());
+        RgbImage::from_pixel(16, 16, image::Rgb([200, 180, 150]))
+            .write_to(&mut bytes, format)
+            .unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn images_pass() {
+        for (content_type, format) in [
+            (Some("image/jpeg"), ImageFormat::Jpeg),
+            (Some("image/png"), ImageFormat::Png),
+            (Some("application/octet-stream"), ImageFormat::Jpeg),
+            (None, ImageFormat::Png),
+        ] {
+            let bytes = encode(format);
+            assert!(check_image(content_type, &bytes).is_ok(), "{:?}", format);
+        }
+        // Padding after the end marker
+        let mut bytes = encode(ImageFormat::Jpeg);
+        bytes.extend_from_slice(&[0; 8]);
+        assert!(check_image(Some("image/jpeg"), &bytes).is_ok());
+    }
+
+    #[test]
+    fn error_pages_are_retried() {
+        let html = b"<!DOCTYPE html><html><body>Session expired</body></html>";
+        let e = check_image(Some("text/html; charset=utf-8"), html).unwrap_err();
+        assert!(e.retryable);
+        assert_eq!(
+            e.message,
+            "unexpected content type text/html; charset=utf-8"
+        );
+        // Served as an image all the same
+        let e = check_image(Some("image/jpeg"), html).unwrap_err();
+        assert!(e.retryable);
+        assert_eq!(e.message, "response of 56 bytes is not an image");
+    }
+
+    #[test]
+    fn wrong_content_type() {
+        let e = check_image(Some("application/json"), &encode(ImageFormat::Jpeg)).unwrap_err();
+        assert!(e.retryable);
+        assert_eq!(e.message, "unexpected content type application/json");
+    }
+
+    #[test]
+    fn truncated_and_empty_bodies() {
+        let e = check_image(Some("image/jpeg"), b"").unwrap_err();
+        assert!(e.retryable);
+        assert_eq!(e.message, "response of 0 bytes is not an image");
+        for format in [ImageFormat::Jpeg, ImageFormat::Png] {
+            let bytes = encode(format);
+            let half = &bytes[..bytes.len() / 2];
+            let e = check_image(None, half).unwrap_err();
+            assert!(e.retryable, "{:?}", format);
+            assert_eq!(
+                e.message,
+                format!("image of {} bytes is truncated", half.len())
+            );
+        }
+    }
+
+    #[test]
+    fn backoff_grows_up_to_the_cap() {
+        let policy = RetryPolicy::default();
+        let ceilings: Vec<u64> = (0..7)
+            .map(|attempt| policy.ceiling(attempt).as_secs())
+            .collect();
+        assert_eq!(ceilings, [1, 2, 4, 8, 16, 30, 30]);
+        assert_eq!(policy.ceiling(u32::MAX), policy.max_delay);
+        for attempt in [0, 3, 10, 40] {
+            for _ in 0..100 {
+                assert!(policy.delay(attempt) <= policy.ceiling(attempt));
             }
         }
-        runtime.shutdown_background();
-        success
+    }
+
+    #[test]
+    fn statuses() {
+        assert!(check_status(StatusCode::OK).is_ok());
+        for status in [
+            StatusCode::REQUEST_TIMEOUT,
+            StatusCode::TOO_MANY_REQUESTS,
+            StatusCode::BAD_GATEWAY,
+            StatusCode::SERVICE_UNAVAILABLE,
+        ] {
+            assert!(check_status(status).unwrap_err().retryable, "{}", status);
+        }
+        for status in [StatusCode::FORBIDDEN, StatusCode::NOT_FOUND] {
+            let e = check_status(status).unwrap_err();
+            assert!(!e.retryable, "{}", status);
+            assert_eq!(e.message, format!("HTTP status {}", status));
+        }
     }
 }
@@ -0,0 +1,163 @@
+use thiserror::Error;
+
+/// Everything that can go wrong while logging in or turning a book URL into a download task.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("the token has expired ({0}), please log in again")]
+    InvalidToken(String),
+    #[error("no token, pass --token, set THUBOOK_TOKEN or log in first")]
+    MissingToken,
+    #[error("login failed: {0}")]
+    LoginFailed(String),
+    #[error("could not read from the terminal: {0}")]
+    Prompt(std::io::Error),
+    #[error(
+        "not a book URL or ID: \"{0}\", expected https://ereserves.lib.tsinghua.edu.cn/bookDetail/<id> or the bare ID"
+    )]
+    InvalidBookUrl(String),
+    #[error("invalid selection: \"{0}\", expected chapters or pages like 3-5, 1,4,7 or 2:10-20")]
+    InvalidSelection(String),
+    #[error("the selection includes no page of the book")]
+    EmptySelection,
+    #[error("cannot read the images in {}: {source}", dir.display())]
+    ImageDir {
+        dir: std::path::PathBuf,
+        source: std::io::Error,
+    },
+    #[error("cannot create the download directory {}: {source}", dir.display())]
+    DownloadDir {
+        dir: std::path::PathBuf,
+        source: std::io::Error,
+    },
+    #[error("no page images like 1_0.jpg in {}", .0.display())]
+    NoImages(std::path::PathBuf),
+    #[error("invalid file name template {0}")]
+    InvalidTemplate(String),
+    #[error("{} already exists, pass --overwrite to replace it", .0.display())]
+    OutputExists(std::path::PathBuf),
+    #[error("book {0} was not found")]
+    BookNotFound(String),
+    #[error("book {0} has no online reading URL")]
+    NoReadUrl(String),
+    #[error("the reader did not set the {0} cookie")]
+    MissingCookie(&'static str),
+    #[error("the reader did not redirect to the book page")]
+    MissingRedirect,
+    #[error("no scan id in the reader page of book {0}")]
+    ScanIdNotFound(String),
+    #[error("unexpected response from {endpoint}: no {pointer}")]
+    UnexpectedApiShape {
+        endpoint: &'static str,
+        pointer: &'static str,
+    },
+    #[error("invalid JSON from {endpoint}: {source}")]
+    Json {
+        endpoint: &'static str,
+        source: serde_json::Error,
+    },
+    #[error("network error: {0}")]
+    Http(#[from] reqwest::Error),
+}
+
+impl Error {
+    /// The process exit code for this error, one per kind of failure so scripts can tell an
+    /// expired token from a wrong URL. 1 is left for other errors, 2 for invalid arguments
+    /// and 130 for Ctrl+C, see the README for the table.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::InvalidToken(_) => 3,
+            Error::BookNotFound(_) => 4,
+            Error::NoReadUrl(_) => 5,
+            Error::UnexpectedApiShape { .. } | Error::Json { .. } => 6,
+            Error::Http(_) => 7,
+            Error::MissingToken => 8,
+            Error::LoginFailed(_) => 9,
+            Error::Prompt(_) => 10,
+            Error::InvalidBookUrl(_) => 11,
+            Error::InvalidSelection(_) => 12,
+            Error::EmptySelection => 13,
+            Error::InvalidTemplate(_) => 14,
+            Error::OutputExists(_) => 15,
+            Error::ImageDir { .. } => 16,
+            Error::NoImages(_) => 17,
+            Error::DownloadDir { .. } => 18,
+            Error::MissingCookie(_) | Error::MissingRedirect | Error::ScanIdNotFound(_) => 19,
+        }
+    }
+
+    /// Whether the remaining books of a batch would fail the same way.
+    pub fn stops_batch(&self) -> bool {
+        matches!(self, Error::InvalidToken(_) | Error::MissingToken)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, path::PathBuf};
+
+    use super::*;
+
+    #[test]
+    fn exit_codes() {
+        let http = reqwest::Client::new().get("not a url").build().unwrap_err();
+        let json = serde_json::from_str::<u8>("x").unwrap_err();
+        let io = || io::Error::from(io::ErrorKind::NotFound);
+        let errors = [
+            (Error::InvalidToken("401".to_owned()), 3),
+            (Error::BookNotFound("b1".to_owned()), 4),
+            (Error::NoReadUrl("b1".to_owned()), 5),
+            (
+                Error::UnexpectedApiShape {
+                    endpoint: "search",
+                    pointer: "/data",
+                },
+                6,
+            ),
+            (
+                Error::Json {
+                    endpoint: "search",
+                    source: json,
+                },
+                6,
+            ),
+            (Error::Http(http), 7),
+            (Error::MissingToken, 8),
+            (Error::LoginFailed("wrong password".to_owned()), 9),
+            (Error::Prompt(io()), 10),
+            (Error::InvalidBookUrl("x".to_owned()), 11),
+            (Error::InvalidSelection("3-".to_owned()), 12),
+            (Error::EmptySelection, 13),
+            (Error::InvalidTemplate("{name}".to_owned()), 14),
+            (Error::OutputExists(PathBuf::from("a.pdf")), 15),
+            (
+                Error::ImageDir {
+                    dir: PathBuf::from("book"),
+                    source: io(),
+                },
+                16,
+            ),
+            (Error::NoImages(PathBuf::from("book")), 17),
+            (
+                Error::DownloadDir {
+                    dir: PathBuf::from("book"),
+                    source: io(),
+                },
+                18,
+            ),
+            (Error::MissingCookie("BotuReadKernel"), 19),
+            (Error::MissingRedirect, 19),
+            (Error::ScanIdNotFound("b1".to_owned()), 19),
+        ];
+        for (error, code) in &errors {
+            assert_eq!(error.exit_code(), *code, "{}", error);
+            // Left for other errors, clap and Ctrl+C
+            assert!(![0, 1, 2, 130].contains(code));
+        }
+        let stopping: Vec<u8> = errors
+            .iter()
+            .filter(|(error, _)| error.stops_batch())
+            .map(|(_, code)| *code)
+            .collect();
+        assert_eq!(stopping, [3, 8]);
+    }
+}
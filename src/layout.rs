@@ -0,0 +1,304 @@
+use std::path::{Path, PathBuf};
+
+use tracing::warn;
+
+use crate::{
+    book_id,
+    error::Error,
+    manifest::{Manifest, sanitize_file_name},
+    selection::Selection,
+    template::Template,
+};
+
+/// One book to download, with the PDF name given in the list, if any.
+pub struct Job {
+    pub url: String,
+    pub name: Option<String>,
+    /// The subdirectory of the downloads the PDF goes to, like the course of a shelf.
+    pub folder: Option<String>,
+}
+
+impl Job {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            name: None,
+            folder: None,
+        }
+    }
+}
+
+/// Where the images and the PDFs of the books go and which of their pages.
+pub struct Layout {
+    /// The directory the images are downloaded to, each book in a folder named after its ID.
+    pub work_dir: PathBuf,
+    pub output_dir: PathBuf,
+    /// The name of the PDFs of the books without a name of their own.
+    pub template: Template,
+    /// Replace an existing PDF instead of numbering the new one.
+    pub overwrite: bool,
+    pub selection: Selection,
+}
+
+impl Layout {
+    /// The whole books in `work_dir`, with the PDFs next to the images, named after the title.
+    pub fn new(work_dir: PathBuf) -> Self {
+        Self {
+            output_dir: work_dir.clone(),
+            work_dir,
+            template: Template::default(),
+            overwrite: false,
+            selection: Selection::default(),
+        }
+    }
+
+    /// Where the PDF of `job` goes: named after the output name from the book list if any,
+    /// after the template and the selection otherwise, in the folder of the job under the
+    /// output directory. `None` if neither is known.
+    pub fn pdf(&self, job: &Job, manifest: Option<&Manifest>) -> Option<PathBuf> {
+        let dir = match &job.folder {
+            Some(folder) => self.output_dir.join(sanitize_file_name(folder)),
+            None => self.output_dir.clone(),
+        };
+        let file_name = match &job.name {
+            Some(name) => {
+                let name = name.strip_suffix(".pdf").unwrap_or(name);
+                format!("{}.pdf", sanitize_file_name(name))
+            }
+            None => manifest?.pdf_file_name(&self.template, &self.selection),
+        };
+        Some(dir.join(file_name))
+    }
+
+    /// `pdf_path` itself with `overwrite` or if it does not exist yet, otherwise the first
+    /// free `<name> (<n>).pdf` next to it.
+    pub fn writable(&self, pdf_path: &Path) -> PathBuf {
+        if self.overwrite || !pdf_path.exists() {
+            return pdf_path.to_path_buf();
+        }
+        let stem = pdf_path.file_stem().unwrap_or_default().to_string_lossy();
+        let free = (1..)
+            .map(|number| pdf_path.with_file_name(format!("{} ({}).pdf", stem, number)))
+            .find(|path| !path.exists())
+            .unwrap();
+        warn!(
+            "{} already exists, saving to {} instead, pass --overwrite to replace it",
+            pdf_path.display(),
+            free.display()
+        );
+        free
+    }
+
+    /// The PDF to write: `explicit` as given, failing if it exists without `overwrite`, or
+    /// else `default` numbered by [`Layout::writable`].
+    pub fn output(&self, explicit: Option<&Path>, default: &Path) -> Result<PathBuf, Error> {
+        match explicit {
+            Some(explicit) if !self.overwrite && explicit.exists() => {
+                Err(Error::OutputExists(explicit.to_path_buf()))
+            }
+            Some(explicit) => Ok(explicit.to_path_buf()),
+            None => Ok(self.writable(default)),
+        }
+    }
+
+    /// The title and the output of a book finished by an earlier run: the PDF when
+    /// converting, the download directory with all selected pages otherwise. Nothing is
+    /// finished when converting with `overwrite`, as the PDF is to be replaced.
+    pub fn finished(&self, job: &Job, convert: bool) -> Option<(String, PathBuf)> {
+        if convert && self.overwrite {
+            return None;
+        }
+        // A named PDF is found even without the manifest, like after --del-img of old runs.
+        if convert
+            && let Some(pdf) = self.pdf(job, None)
+            && pdf.exists()
+        {
+            return Some((job.name.clone().unwrap(), pdf));
+        }
+        let (dir, mut manifest) = Manifest::find(&self.work_dir, &book_id::parse(&job.url).ok()?)?;
+        let pdf = self.pdf(job, Some(&manifest))?;
+        // The pages may be gone with --del-img, but the manifest keeps the PDF made of them.
+        if convert
+            && let Some(converted) = manifest.pdfs.get(&pdf)
+            && converted.exists()
+        {
+            return Some((manifest.title, converted.clone()));
+        }
+        manifest.refresh(&dir);
+        if !manifest.is_complete(&self.selection) {
+            return None;
+        }
+        if !convert {
+            return Some((manifest.title, dir));
+        }
+        pdf.exists().then_some((manifest.title, pdf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::manifest::{ChapterEntry, PageEntry};
+
+    fn manifest(title: &str) -> Manifest {
+        Manifest {
+            book_id: "book0001".to_owned(),
+            book_real_id: "r1".to_owned(),
+            title: title.to_owned(),
+            authors: Vec::new(),
+            publisher: String::new(),
+            isbn: String::new(),
+            publish_date: String::new(),
+            chapters: vec![ChapterEntry {
+                emid: "e1".to_owned(),
+                title: "Chapter 1".to_owned(),
+                level: 1,
+                pages: vec![PageEntry {
+                    hfs_key: "k1".to_owned(),
+                    file_name: "1_1.jpg".to_owned(),
+                    downloaded: false,
+                }],
+            }],
+            pdfs: Default::default(),
+        }
+    }
+
+    #[test]
+    fn pdf_names() {
+        let layout = Layout::new(PathBuf::from("downloads"));
+        let book = manifest("Algebra");
+        let job = Job::new("book0001".to_owned());
+        assert_eq!(layout.pdf(&job, None), None);
+        assert_eq!(
+            layout.pdf(&job, Some(&book)),
+            Some(PathBuf::from("downloads/Algebra.pdf"))
+        );
+        let job = Job {
+            name: Some("A/B.pdf".to_owned()),
+            folder: Some("Math: 101".to_owned()),
+            ..Job::new("book0001".to_owned())
+        };
+        assert_eq!(
+            layout.pdf(&job, Some(&book)),
+            Some(PathBuf::from("downloads/Math_ 101/A_B.pdf"))
+        );
+    }
+
+    #[test]
+    fn existing_outputs() {
+        let dir = std::env::temp_dir().join(format!("thubookrs-outputs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let pdf = dir.join("book.pdf");
+        let mut layout = Layout::new(dir.clone());
+        assert_eq!(layout.output(None, &pdf).unwrap(), pdf);
+        assert_eq!(layout.output(Some(&pdf), &pdf).unwrap(), pdf);
+
+        fs::write(&pdf, b"").unwrap();
+        fs::write(dir.join("book (1).pdf"), b"").unwrap();
+        assert_eq!(layout.output(None, &pdf).unwrap(), dir.join("book (2).pdf"));
+        assert!(matches!(
+            layout.output(Some(&pdf), &pdf),
+            Err(Error::OutputExists(path)) if path == pdf
+        ));
+
+        layout.overwrite = true;
+        assert_eq!(layout.output(None, &pdf).unwrap(), pdf);
+        assert_eq!(layout.output(Some(&pdf), &pdf).unwrap(), pdf);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn download_numbers_and_convert_fails() {
+        let dir = std::env::temp_dir().join(format!("thubookrs-numbers-{}", std::process::id()));
+        let layout = Layout::new(dir.clone());
+        let job = Job {
+            folder: Some("Math".to_owned()),
+            ..Job::new("book0001".to_owned())
+        };
+        let name = layout.pdf(&job, Some(&manifest("Algebra"))).unwrap();
+        fs::create_dir_all(name.parent().unwrap()).unwrap();
+        fs::write(&name, b"").unwrap();
+        // download: the name of the job is numbered
+        assert_eq!(
+            layout.output(None, &name).unwrap(),
+            dir.join("Math").join("Algebra (1).pdf")
+        );
+        // convert -o: the name given is not replaced
+        assert!(matches!(
+            layout.output(Some(&name), &dir.join("r1.pdf")),
+            Err(Error::OutputExists(path)) if path == name
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn finished_books() {
+        let dir = std::env::temp_dir().join(format!("thubookrs-finished-{}", std::process::id()));
+        let book_dir = dir.join("r1");
+        fs::create_dir_all(&book_dir).unwrap();
+        manifest("Algebra").save(&book_dir).unwrap();
+        let layout = Layout::new(dir.clone());
+        let job = Job::new("book0001".to_owned());
+        assert_eq!(layout.finished(&job, false), None);
+
+        fs::write(book_dir.join("1_1.jpg"), b"").unwrap();
+        assert_eq!(
+            layout.finished(&job, false),
+            Some(("Algebra".to_owned(), book_dir.clone()))
+        );
+        assert_eq!(layout.finished(&job, true), None);
+
+        fs::write(dir.join("Algebra.pdf"), b"").unwrap();
+        assert_eq!(
+            layout.finished(&job, true),
+            Some(("Algebra".to_owned(), dir.join("Algebra.pdf")))
+        );
+        assert_eq!(
+            layout.finished(&Job::new("book0002".to_owned()), true),
+            None
+        );
+        let overwrite = Layout {
+            overwrite: true,
+            ..Layout::new(dir.clone())
+        };
+        assert_eq!(overwrite.finished(&job, true), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn finished_without_the_pages() {
+        let dir = std::env::temp_dir().join(format!("thubookrs-del-img-{}", std::process::id()));
+        let book_dir = dir.join("r1");
+        fs::create_dir_all(&book_dir).unwrap();
+        fs::write(book_dir.join("1_1.jpg"), b"").unwrap();
+        // Numbered as Algebra.pdf is another book, then converted with --del-img
+        fs::write(dir.join("Algebra.pdf"), b"").unwrap();
+        fs::write(dir.join("Algebra (1).pdf"), b"").unwrap();
+        let mut book = manifest("Algebra");
+        book.pdfs
+            .insert(dir.join("Algebra.pdf"), dir.join("Algebra (1).pdf"));
+        book.remove_pages(&book_dir).unwrap();
+        book.save(&book_dir).unwrap();
+        assert!(!book_dir.join("1_1.jpg").exists());
+
+        let layout = Layout::new(dir.clone());
+        let job = Job::new("book0001".to_owned());
+        assert_eq!(
+            layout.finished(&job, true),
+            Some(("Algebra".to_owned(), dir.join("Algebra (1).pdf")))
+        );
+        // Fetching needs the pages, and other chapters are another PDF
+        assert_eq!(layout.finished(&job, false), None);
+        let chapters = Layout {
+            selection: Selection::parse(Some("1"), None).unwrap(),
+            ..Layout::new(dir.clone())
+        };
+        assert_eq!(chapters.finished(&job, true), None);
+        // Nor is the book finished once its PDF is gone
+        fs::remove_file(dir.join("Algebra (1).pdf")).unwrap();
+        assert_eq!(layout.finished(&job, true), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
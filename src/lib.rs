@@ -0,0 +1,66 @@
+//! Download e-books from <https://ereserves.lib.tsinghua.edu.cn> and convert them to PDF.
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! use thubookrs::{Client, convert::ConvertOptions, manifest::Manifest, selection::Selection};
+//! use tokio_util::sync::CancellationToken;
+//!
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! let client = Client::builder()
+//!     .concurrency(8)
+//!     .requests_per_second(5.0)
+//!     .on_progress(|event| println!("{:?}", event))
+//!     .build()?;
+//! let task = client
+//!     .parse("https://ereserves.lib.tsinghua.edu.cn/bookDetail/xxx", "eyJhb...")
+//!     .await?;
+//! let save_dir = Path::new("downloads").join(&task.book_real_id);
+//! let manifest = Manifest::new(&task, &save_dir);
+//! // Chapters 3 to 5 only, see `Selection` for the syntax
+//! let selection = Selection::parse(Some("3-5"), None)?;
+//! // Cancel to stop, like on Ctrl+C
+//! let cancel = CancellationToken::new();
+//! let failures = client
+//!     .download(task, &save_dir, &selection, cancel.clone())
+//!     .await?;
+//! assert!(failures.is_empty());
+//! client
+//!     .convert(
+//!         &save_dir,
+//!         Path::new(&manifest.pdf_file_name(&Default::default(), &selection)),
+//!         &ConvertOptions {
+//!             selection,
+//!             ..Default::default()
+//!         },
+//!         &manifest.bookmarks(),
+//!         &manifest.metadata(),
+//!         &cancel,
+//!     )
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod book_id;
+pub mod capture;
+pub mod client;
+pub mod convert;
+pub mod download;
+pub mod error;
+pub mod layout;
+pub mod login;
+pub mod manifest;
+pub mod pre_process;
+pub mod progress;
+pub mod selection;
+pub mod session;
+pub mod template;
+pub mod throttle;
+
+mod bilevel;
+mod pdf;
+mod redact;
+
+pub use client::{Client, ClientBuilder};
+pub use error::Error;
@@ -0,0 +1,436 @@
+use std::{collections::HashMap, io, sync::Arc};
+
+use reqwest::{Client, ClientBuilder, Response, Url, header};
+use reqwest_cookie_store::{CookieStore, CookieStoreMutex};
+use scraper::{Html, Selector};
+use serde_json::Value;
+use tracing::debug;
+
+use crate::{error::Error, throttle::Throttle};
+
+/// Where the ereserves website sends users to the unified authentication.
+const LOGIN_URL: &str = "https://ereserves.lib.tsinghua.edu.cn/authcenter/toLoginPage";
+/// The unified authentication server.
+const ID_URL: &str = "https://id.tsinghua.edu.cn";
+/// Gives up on redirect loops.
+const MAX_STEPS: usize = 20;
+
+/// How the second factor of the unified authentication is delivered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TwoFactor {
+    WeChat,
+    Mobile,
+    Totp,
+}
+
+impl TwoFactor {
+    /// The name the authentication server uses for this method.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TwoFactor::WeChat => "wechat",
+            TwoFactor::Mobile => "mobile",
+            TwoFactor::Totp => "totp",
+        }
+    }
+}
+
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+    pub two_factor: TwoFactor,
+}
+
+/// Logs in through the unified authentication and captures the Jcclient token that the
+/// ereserves website receives in the final `/index?token=xxx` redirect.
+pub struct Authenticator {
+    client: Client,
+    cookie_store: Arc<CookieStoreMutex>,
+    throttle: Arc<Throttle>,
+}
+
+impl Authenticator {
+    pub fn new(throttle: Arc<Throttle>) -> Result<Self, Error> {
+        let cookie_store = Arc::new(CookieStoreMutex::new(CookieStore::default()));
+        let mut default_headers = header::HeaderMap::new();
+        default_headers.insert("User-Agent", header::HeaderValue::from_static("Mozilla/5.0 (Windows NT 6.1; WOW64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/39.0.2171.71 Safari/537.36"));
+        Ok(Self {
+            // Redirects are followed by hand, the token only shows up in one of them.
+            client: ClientBuilder::new()
+                .redirect(reqwest::redirect::Policy::none())
+                .cookie_provider(Arc::clone(&cookie_store))
+                .default_headers(default_headers)
+                .build()?,
+            cookie_store,
+            throttle,
+        })
+    }
+
+    /// Logs in and returns the token. `code` is asked for the verification code once the
+    /// server wants the second factor, after the code was sent for WeChat and mobile.
+    pub async fn login(
+        &self,
+        credentials: &Credentials,
+        code: impl Fn(TwoFactor) -> io::Result<String>,
+    ) -> Result<String, Error> {
+        let result = self.run(credentials, code).await;
+        self.cookie_store.lock().unwrap().clear();
+        result
+    }
+
+    async fn run(
+        &self,
+        credentials: &Credentials,
+        code: impl Fn(TwoFactor) -> io::Result<String>,
+    ) -> Result<String, Error> {
+        let mut res = self.throttle.send(self.client.get(LOGIN_URL)).await?;
+        let mut submitted = false;
+        for _ in 0..MAX_STEPS {
+            let url = res.url().clone();
+            if let Some(token) = find_token(&url) {
+                return Ok(token);
+            }
+            if let Some(location) = redirect_target(&res) {
+                if let Some(token) = find_token(&location) {
+                    return Ok(token);
+                }
+                res = self.throttle.send(self.client.get(location)).await?;
+                continue;
+            }
+            if url.path().contains("/doubleAuth/") {
+                let next = self.two_factor(credentials.two_factor, &code).await?;
+                res = self.throttle.send(self.client.get(next)).await?;
+                continue;
+            }
+            let status = res.status();
+            let html = res.text().await?;
+            if let Some(form) = LoginForm::parse(&html, &url) {
+                if submitted {
+                    return Err(Error::LoginFailed(
+                        form.message
+                            .unwrap_or_else(|| "wrong username or password".to_owned()),
+                    ));
+                }
+                submitted = true;
+                let mut fields = form.hidden;
+                fields.insert("i_user".to_owned(), credentials.username.clone());
+                fields.insert("i_pass".to_owned(), credentials.password.clone());
+                res = self
+                    .throttle
+                    .send(self.client.post(form.action).form(&fields))
+                    .await?;
+                continue;
+            }
+            match script_redirect(&html).and_then(|target| url.join(&target).ok()) {
+                Some(target) => {
+                    res = self.throttle.send(self.client.get(target)).await?;
+                }
+                None => {
+                    return Err(Error::LoginFailed(format!(
+                        "unexpected {} page at {}",
+                        status,
+                        url.path()
+                    )));
+                }
+            }
+        }
+        Err(Error::LoginFailed("too many redirects".to_owned()))
+    }
+
+    /// Sends the verification code if needed, checks the one entered and returns where the
+    /// server continues.
+    async fn two_factor(
+        &self,
+        method: TwoFactor,
+        code: &impl Fn(TwoFactor) -> io::Result<String>,
+    ) -> Result<Url, Error> {
+        if method != TwoFactor::Totp {
+            let res = self
+                .throttle
+                .send(
+                    self.client
+                        .post(format!("{}/b/doubleAuth/personal/send", ID_URL))
+                        .form(&[("type", method.name())]),
+                )
+                .await?;
+            check_result("doubleAuth/personal/send", &res.text().await?)?;
+        }
+        let code = code(method).map_err(Error::Prompt)?;
+        let res = self
+            .throttle
+            .send(
+                self.client
+                    .post(format!("{}/b/doubleAuth/personal/check", ID_URL))
+                    .form(&[("type", method.name()), ("vericode", code.trim())]),
+            )
+            .await?;
+        let endpoint = "doubleAuth/personal/check";
+        let v = check_result(endpoint, &res.text().await?)?;
+        let next = v["object"]["redirectUrl"]
+            .as_str()
+            .ok_or(Error::UnexpectedApiShape {
+                endpoint,
+                pointer: "/object/redirectUrl",
+            })?;
+        Url::parse(ID_URL)
+            .and_then(|base| base.join(next))
+            .map_err(|_| Error::LoginFailed(format!("invalid redirect {}", next)))
+    }
+}
+
+/// The token is handed to the ereserves website as `?token=xxx`.
+fn find_token(url: &Url) -> Option<String> {
+    if url.host_str() != Some("ereserves.lib.tsinghua.edu.cn") {
+        return None;
+    }
+    url.query_pairs()
+        .find(|(key, value)| key == "token" && !value.is_empty())
+        .map(|(_, value)| value.into_owned())
+}
+
+fn redirect_target(res: &Response) -> Option<Url> {
+    if !res.status().is_redirection() {
+        return None;
+    }
+    let location = res.headers().get(header::LOCATION)?.to_str().ok()?;
+    res.url().join(location).ok()
+}
+
+/// The authentication server finishes some steps with a page that redirects by script, with
+/// `location.href = '...'`, `location = '...'` or `location.replace('...')` in a script, or a
+/// refresh meta tag.
+fn script_redirect(html: &str) -> Option<String> {
+    let doc = Html::parse_document(html);
+    let script_selector = Selector::parse("script").unwrap();
+    for script in doc.select(&script_selector) {
+        let code = strip_js_comments(&script.text().collect::<String>());
+        if let Some(target) = location_assignment(&code) {
+            return Some(target);
+        }
+    }
+    let selector = Selector::parse("meta[http-equiv=refresh i]").unwrap();
+    let content = doc.select(&selector).next()?.value().attr("content")?;
+    let (_, target) = content.split_once('=')?;
+    Some(target.trim_matches(['\'', '"', ' ']).to_owned())
+}
+
+/// Removes `//` and `/* */` comments outside of string literals, so a commented out redirect
+/// is not followed.
+fn strip_js_comments(code: &str) -> String {
+    let mut output = String::with_capacity(code.len());
+    let mut chars = code.chars().peekable();
+    let mut quote = None;
+    while let Some(c) = chars.next() {
+        match quote {
+            Some(q) => {
+                output.push(c);
+                if c == '\\' {
+                    output.extend(chars.next());
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None if c == '/' && chars.peek() == Some(&'/') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        output.push('\n');
+                        break;
+                    }
+                }
+            }
+            None if c == '/' && chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                for c in chars.by_ref() {
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+                output.push(' ');
+            }
+            None => {
+                if matches!(c, '"' | '\'' | '`') {
+                    quote = Some(c);
+                }
+                output.push(c);
+            }
+        }
+    }
+    output
+}
+
+/// The string assigned to `location` or `location.href`, or passed to `location.replace`.
+fn location_assignment(code: &str) -> Option<String> {
+    let mut from = 0;
+    while let Some(found) = code[from..].find("location") {
+        let start = from + found;
+        from = start + "location".len();
+        let before = code[..start].chars().next_back();
+        if before.is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '$') {
+            continue;
+        }
+        let rest = &code[from..];
+        let rest = rest.strip_prefix(".href").unwrap_or(rest).trim_start();
+        let value = if let Some(value) = rest.strip_prefix('=') {
+            if value.starts_with('=') {
+                // A comparison, not an assignment
+                continue;
+            }
+            value
+        } else if let Some(value) = rest
+            .strip_prefix(".replace(")
+            .or_else(|| rest.strip_prefix(".assign("))
+        {
+            value
+        } else {
+            continue;
+        };
+        let value = value.trim_start();
+        let Some(quote) = value.chars().next().filter(|c| matches!(c, '"' | '\'')) else {
+            continue;
+        };
+        if let Some(end) = value[1..].find(quote) {
+            return Some(value[1..end + 1].to_owned());
+        }
+    }
+    None
+}
+
+fn check_result(endpoint: &'static str, text: &str) -> Result<Value, Error> {
+    let v: Value = serde_json::from_str(text).map_err(|source| Error::Json { endpoint, source })?;
+    debug!("{} result: {}", endpoint, v["result"]);
+    if v["result"].as_str() == Some("success") {
+        Ok(v)
+    } else {
+        Err(Error::LoginFailed(
+            v["msg"]
+                .as_str()
+                .unwrap_or("verification failed")
+                .to_owned(),
+        ))
+    }
+}
+
+/// The username and password form of the authentication server.
+struct LoginForm {
+    action: Url,
+    hidden: HashMap<String, String>,
+    /// The error shown after a failed attempt.
+    message: Option<String>,
+}
+
+impl LoginForm {
+    fn parse(html: &str, url: &Url) -> Option<Self> {
+        let doc = Html::parse_document(html);
+        let form_selector = Selector::parse("form").unwrap();
+        let input_selector = Selector::parse("input[name]").unwrap();
+        let form = doc.select(&form_selector).find(|form| {
+            form.select(&input_selector)
+                .any(|input| input.value().attr("name") == Some("i_user"))
+        })?;
+        let action = url.join(form.value().attr("action").unwrap_or("")).ok()?;
+        let hidden = form
+            .select(&input_selector)
+            .filter(|input| input.value().attr("type") == Some("hidden"))
+            .filter_map(|input| {
+                Some((
+                    input.value().attr("name")?.to_owned(),
+                    input.value().attr("value").unwrap_or("").to_owned(),
+                ))
+            })
+            .collect();
+        let message_selector = Selector::parse("#msg_note").unwrap();
+        let message = doc
+            .select(&message_selector)
+            .next()
+            .map(|element| element.text().collect::<String>().trim().to_owned())
+            .filter(|message| !message.is_empty());
+        Some(Self {
+            action,
+            hidden,
+            message,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOGIN_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>清华大学用户电子身份服务系统</title></head>
+<body>
+<form id="theform" name="theform" action="/do/off/ui/auth/login/check" method="post">
+  <input type="hidden" name="lt" value="LT-1234">
+  <input type="hidden" name="execution" value="e1s1">
+  <input type="text" name="i_user" id="i_user">
+  <input type="password" name="i_pass" id="i_pass">
+  <input type="hidden" name="singleLogin">
+  <div id="msg_note">用户名或密码错误</div>
+</form>
+<form action="/search"><input type="text" name="q"></form>
+</body>
+</html>"#;
+
+    #[test]
+    fn login_form() {
+        let url = Url::parse("https://id.tsinghua.edu.cn/do/off/ui/auth/login/form/abc").unwrap();
+        let form = LoginForm::parse(LOGIN_PAGE, &url).unwrap();
+        assert_eq!(
+            form.action.as_str(),
+            "https://id.tsinghua.edu.cn/do/off/ui/auth/login/check"
+        );
+        assert_eq!(form.hidden.len(), 3);
+        assert_eq!(form.hidden["lt"], "LT-1234");
+        assert_eq!(form.hidden["execution"], "e1s1");
+        assert_eq!(form.hidden["singleLogin"], "");
+        assert_eq!(form.message.as_deref(), Some("用户名或密码错误"));
+
+        let other = "<form action=\"/search\"><input name=\"q\"></form>";
+        assert!(LoginForm::parse(other, &url).is_none());
+    }
+
+    #[test]
+    fn script_redirects() {
+        for (html, target) in [
+            (
+                "<script>window.location.href = 'https://ereserves.lib.tsinghua.edu.cn/index?token=t1';</script>",
+                "https://ereserves.lib.tsinghua.edu.cn/index?token=t1",
+            ),
+            (
+                "<script type=\"text/javascript\">\n  location.replace(\"/f/login/success\");\n</script>",
+                "/f/login/success",
+            ),
+            ("<script>top.location=\"/next\"</script>", "/next"),
+            (
+                "<head><meta http-equiv=\"Refresh\" content=\"0; url='/after'\"></head>",
+                "/after",
+            ),
+        ] {
+            assert_eq!(script_redirect(html).as_deref(), Some(target), "{}", html);
+        }
+    }
+
+    #[test]
+    fn no_redirect() {
+        for html in [
+            LOGIN_PAGE,
+            // Commented out or inside a comparison, not a redirect
+            "<script>// location.href = '/old';\nvar a = 1;</script>",
+            "<script>/* location.replace('/old') */</script>",
+            "<!-- <script>location.href = '/old'</script> -->",
+            "<script>if (location.href == '/here') { show(); }</script>",
+            "<script>var mylocation = '/x'; console.log(location.href);</script>",
+            "<p>Set location.href = '/text' to redirect.</p>",
+        ] {
+            assert_eq!(script_redirect(html), None, "{}", html);
+        }
+        // A URL in a string is not a comment
+        assert_eq!(
+            script_redirect("<script>location.href = 'https://a.example/b'; // done</script>")
+                .as_deref(),
+            Some("https://a.example/b")
+        );
+    }
+}
@@ -1,63 +1,701 @@
-use std::fs;
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
 
-use clap::{Arg, ArgAction, command, value_parser};
+use clap::{
+    Arg, ArgAction, ArgMatches, Command,
+    builder::{PossibleValuesParser, TypedValueParser},
+    command, value_parser,
+};
 use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, level_filters::LevelFilter, warn};
 
-mod convert;
-mod download;
-mod pre_process;
+use thubookrs::{
+    Client, book_id,
+    capture::Capture,
+    convert,
+    convert::ColorMode,
+    download, error,
+    layout::{Job, Layout},
+    login::TwoFactor,
+    manifest,
+    progress::Progress,
+    selection::Selection,
+    session::Session,
+    template::Template,
+    throttle::MIN_RATE,
+};
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let matches = command!().version(env!("CARGO_PKG_VERSION")).author("Ricky1911").about(
-        "Download e-book from http://ereserves.lib.tsinghua.edu.cn. By default, the number of threads is four and the temporary images WILL BE preserved. 
-        For example, \"thubookrs https://ereserves.lib.tsinghua.edu.cn/bookDetail/c01e1db11c4041a39db463e810bac8f94af518935a1ec46ef --token eyJhb...\". 
-        Note that you need to manually login the ereserves website and obtain the token from the FIRST request after login, 
-        like \"/index?token=xxx\", due to two-factor authentication (2FA)."
-    )
-    .arg(Arg::new("url").required(true).value_parser(value_parser!(String)))
-    .arg(Arg::new("token").required(true).short('t').long("token").help("Required. The token from the \"/index?token=xxx\".").value_parser(value_parser!(String)))
-    .arg(Arg::new("thread_number").required(false).short('n').help("Optional. The number of threads. [1~16]").value_parser(value_parser!(i32).range(1..17)).default_value("4"))
-    .arg(Arg::new("quality").required(false).short('q').help("Optional. The quality of the generated PDF. The bigger the value, the higher the resolution. [3~10]").value_parser(value_parser!(i32).range(3..11)).default_value("10"))
-    .arg(Arg::new("del_img").required(false).short('d').long("del-img").help("Optional. Delete the temporary images.").action(ArgAction::SetTrue))
-    .arg(Arg::new("auto_resize").required(false).short('r').long("auto-resize").help("Optional. Automatically unify page sizes.").action(ArgAction::SetTrue))
-    .get_matches();
-
-    let url = matches.get_one::<String>("url").unwrap();
-    let token = matches.get_one::<String>("token").unwrap();
-    let thread_number = matches.get_one::<i32>("thread_number").unwrap();
-    let quality = matches.get_one::<i32>("quality").unwrap();
-    let del_img = matches.get_one::<bool>("del_img").unwrap();
-    let auto_resize = matches.get_one::<bool>("auto_resize").unwrap();
-
-    let pre_processor = pre_process::Preprocessor::new()?;
-    let task = pre_processor.parse(url, token).await?;
-    let downloader = download::Downloader::new()?;
+mod cli;
+
+use cli::{
+    batch::{Row, Status},
+    token::{Source, Token},
+};
+
+fn network_args() -> Vec<Arg> {
+    vec![
+        Arg::new("thread_number").required(false).short('n').help("Optional. The number of simultaneous downloads. [1~16]").value_parser(value_parser!(i32).range(1..17)).default_value("4"),
+        Arg::new("retries").required(false).long("retries").help("Optional. How many times a failed page is retried. [0~20]").value_parser(value_parser!(u32).range(0..21)).default_value("3"),
+        Arg::new("timeout").required(false).long("timeout").help("Optional. Seconds to wait for data from the server before retrying.").value_parser(value_parser!(u64).range(1..)).default_value("30"),
+        Arg::new("connect_timeout").required(false).long("connect-timeout").help("Optional. Seconds to wait for a connection before retrying.").value_parser(value_parser!(u64).range(1..)).default_value("10"),
+        Arg::new("rate").required(false).long("rate").help("Optional. The maximum number of requests per second sent to the server, at least 0.01. [default: unlimited]").value_parser(parse_rate),
+        Arg::new("delay").required(false).long("delay").help("Optional. Delay each request by a random number of milliseconds up to this value.").value_parser(value_parser!(u64)).default_value("0"),
+    ]
+}
+
+#[rustfmt::skip]
+fn token_arg() -> Arg {
+    Arg::new("token").required(false).short('t').long("token").env("THUBOOK_TOKEN").hide_env_values(true).help("Optional. The token from the \"/index?token=xxx\". [default: the token saved by the last run]").value_parser(value_parser!(String))
+}
+
+fn parse_rate(text: &str) -> Result<f64, String> {
+    let rate: f64 = text.trim().parse().map_err(|e| format!("{}", e))?;
+    if rate.is_finite() && rate >= MIN_RATE {
+        Ok(rate)
+    } else {
+        Err(format!(
+            "expected a number of requests per second from {}",
+            MIN_RATE
+        ))
+    }
+}
+
+fn parse_color_mode(mode: &str) -> ColorMode {
+    match mode {
+        "auto" => ColorMode::Auto,
+        "gray" => ColorMode::Gray,
+        "bw" => ColorMode::Bw,
+        _ => ColorMode::Color,
+    }
+}
+
+#[rustfmt::skip]
+fn online_args() -> Vec<Arg> {
+    let mut args = vec![
+        Arg::new("url").required_unless_present_any(["bookmarklet", "from_file"]).num_args(1..).help("The book detail pages, downloaded one after another. [default: the page the bookmarklet is clicked on]").value_parser(value_parser!(String)),
+        Arg::new("from_file").required(false).long("from-file").help("Optional. Also download the books listed in this file, one URL or book ID per line, optionally followed by a space and the output name.").value_parser(value_parser!(PathBuf)),
+        token_arg(),
+        Arg::new("bookmarklet").required(false).long("bookmarklet").help("Optional. Instead of --token, print a bookmarklet and wait for it to send the token from the logged-in ereserves page.").action(ArgAction::SetTrue),
+        Arg::new("port").required(false).long("port").help("Optional. The localhost port the bookmarklet sends the token to. [default: any free port]").value_parser(value_parser!(u16)).default_value("0").hide_default_value(true).requires("bookmarklet"),
+    ];
+    args.extend(network_args());
+    args
+}
+
+fn convert_args() -> [Arg; 3] {
+    [
+        Arg::new("quality").required(false).short('q').help("Optional. The quality of the generated PDF. The bigger the value, the higher the resolution. [3~10]").value_parser(value_parser!(i32).range(3..11)).default_value("10"),
+        Arg::new("auto_resize").required(false).short('r').long("auto-resize").help("Optional. Automatically unify page sizes.").action(ArgAction::SetTrue),
+        Arg::new("color_mode").required(false).long("color-mode").help("Optional. Keep the pages in color, make them gray, or black and white (bw) for text. auto picks gray or bw for each page without color.").value_parser(PossibleValuesParser::new(["auto", "color", "gray", "bw"]).map(|mode| parse_color_mode(&mode))).default_value("color"),
+    ]
+}
+
+#[rustfmt::skip]
+fn selection_args() -> [Arg; 2] {
+    [
+        Arg::new("chapters").required(false).long("chapters").help("Optional. Only these chapters, numbered from 1 as \"thubookrs info\" lists them, like \"3-5\" or \"1,4,7\".").value_parser(|text: &str| Selection::parse(Some(text), None).map(|_| text.to_owned())),
+        Arg::new("pages").required(false).long("pages").help("Optional. Only these pages, numbered from 1 through the book like \"10-20\", or within a chapter like \"2:10-20\". Combined with --chapters, the pages of both are included.").value_parser(|text: &str| Selection::parse(None, Some(text)).map(|_| text.to_owned())),
+    ]
+}
+
+#[rustfmt::skip]
+fn work_dir_arg() -> Arg {
+    Arg::new("work_dir").required(false).long("work-dir").help("Optional. The directory the images are downloaded to, each book in a folder named after its ID.").value_parser(value_parser!(PathBuf)).default_value("downloads")
+}
+
+#[rustfmt::skip]
+fn output_args() -> [Arg; 3] {
+    [
+        Arg::new("output_dir").required(false).long("output-dir").help("Optional. The directory the PDFs are saved to. [default: the work directory]").value_parser(value_parser!(PathBuf)),
+        Arg::new("name").required(false).long("name").help("Optional. The PDF file name, filled with the {title}, {author}, {authors}, {publisher}, {year}, {date} and {isbn} of the book, like \"{title} - {author} ({year})\".").value_parser(Template::parse).default_value("{title}"),
+        Arg::new("overwrite").required(false).long("overwrite").help("Optional. Replace an existing PDF of the same name instead of numbering the new one.").action(ArgAction::SetTrue),
+    ]
+}
+
+#[rustfmt::skip]
+fn del_img_arg() -> Arg {
+    Arg::new("del_img").required(false).short('d').long("del-img").help("Optional. Delete the temporary images.").action(ArgAction::SetTrue)
+}
+
+/// Builds the client from the network options of `download` and `fetch`.
+fn client(matches: &ArgMatches, bars: &cli::progress::Bars) -> Result<Client, error::Error> {
+    let retry = download::RetryPolicy {
+        retries: *matches.get_one::<u32>("retries").unwrap(),
+        read_timeout: Duration::from_secs(*matches.get_one::<u64>("timeout").unwrap()),
+        connect_timeout: Duration::from_secs(*matches.get_one::<u64>("connect_timeout").unwrap()),
+        ..Default::default()
+    };
+    let mut builder = Client::builder()
+        .retry(retry)
+        .jitter(Duration::from_millis(
+            *matches.get_one::<u64>("delay").unwrap(),
+        ))
+        .concurrency(*matches.get_one::<i32>("thread_number").unwrap() as usize)
+        .progress(bars.progress());
+    if let Some(rate) = matches.get_one::<f64>("rate") {
+        builder = builder.requests_per_second(*rate);
+    }
+    builder.build()
+}
+
+/// The token from `--token`, `THUBOOK_TOKEN` or the token saved by an earlier run.
+fn token(matches: &ArgMatches) -> Result<Token, error::Error> {
+    match matches.get_one::<String>("token") {
+        Some(token) => Ok(Token::new(token.clone(), Source::Argument)),
+        None => Token::saved(),
+    }
+}
+
+/// The books and the token, from the bookmarklet with `--bookmarklet`, otherwise from
+/// `--token`, `THUBOOK_TOKEN` or the token saved by an earlier run.
+async fn jobs_and_token(
+    matches: &ArgMatches,
+) -> Result<(Vec<Job>, Token), Box<dyn std::error::Error>> {
+    let mut jobs: Vec<Job> = matches
+        .get_many::<String>("url")
+        .into_iter()
+        .flatten()
+        .map(|url| Job::new(url.clone()))
+        .collect();
+    if let Some(path) = matches.get_one::<PathBuf>("from_file") {
+        jobs.extend(cli::batch::read_list(path)?);
+    }
+    if !*matches.get_one::<bool>("bookmarklet").unwrap() {
+        if jobs.is_empty() {
+            return Err(std::io::Error::other("no books to download").into());
+        }
+        return Ok((jobs, token(matches)?));
+    }
+    let capture = Capture::bind(*matches.get_one::<u16>("port").unwrap()).await?;
+    println!(
+        "Save the following line as a bookmark, then open the book on the ereserves website and click the bookmark:"
+    );
+    println!();
+    println!("{}", capture.bookmarklet()?);
+    println!();
+    info!("Waiting for the bookmarklet...");
+    let captured = capture.wait().await?;
+    info!("Token received");
+    if jobs.is_empty() {
+        jobs.push(Job::new(captured.url));
+    }
+    Ok((jobs, Token::new(captured.token, Source::Fresh)))
+}
+
+/// The layout from `--work-dir`, `--output-dir`, `--name`, `--overwrite`, `--chapters` and
+/// `--pages`, with their defaults for the subcommands without some of them.
+fn layout(matches: &ArgMatches) -> Result<Layout, error::Error> {
+    let path = |id| matches.try_get_one::<PathBuf>(id).ok().flatten().cloned();
+    let text = |id| {
+        matches
+            .try_get_one::<String>(id)
+            .ok()
+            .flatten()
+            .map(String::as_str)
+    };
+    let mut layout = Layout::new(path("work_dir").unwrap_or_else(|| PathBuf::from("downloads")));
+    if let Some(output_dir) = path("output_dir") {
+        layout.output_dir = output_dir;
+    }
+    if let Some(template) = matches.try_get_one::<Template>("name").ok().flatten() {
+        layout.template = template.clone();
+    }
+    layout.overwrite = matches
+        .try_get_one::<bool>("overwrite")
+        .ok()
+        .flatten()
+        .is_some_and(|overwrite| *overwrite);
+    layout.selection = Selection::parse(text("chapters"), text("pages"))?;
+    Ok(layout)
+}
+
+fn interrupted() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Interrupted, "Keyboard interrupted")
+}
+
+/// Cancels the returned token on the first Ctrl+C and quits on the second. Once tokio
+/// handles the signal it no longer ends the process, so it is watched for the rest of the run.
+fn watch_ctrl_c() -> CancellationToken {
     let cancel = CancellationToken::new();
-    let save_dir = std::env::current_dir()?
-        .join("downloads")
-        .join(&task.book_real_id);
-    let success = tokio::select! {
-        _ = tokio::signal::ctrl_c() => {
-            cancel.cancel();
-            false
-        },
-        result = downloader.download_imgs(task, &save_dir, *thread_number as usize, cancel.clone()) => { result }
+    let watched = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        warn!("Interrupted, stopping. Press Ctrl+C again to quit at once");
+        watched.cancel();
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+    cancel
+}
+
+/// Downloads, and converts if `convert`, the books one after another with the same client.
+/// A single book fails with its own error, a batch goes on with the next book and prints a
+/// summary at the end. Ctrl+C stops the batch at any step.
+async fn run_jobs(
+    client: &Client,
+    jobs: &[Job],
+    token: &Token,
+    matches: &ArgMatches,
+    convert: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let layout = layout(matches)?;
+    let cancel = watch_ctrl_c();
+    let mut rows = Vec::new();
+    let mut fatal = None;
+    for (index, job) in jobs.iter().enumerate() {
+        if jobs.len() > 1 {
+            info!("Book {}/{}: {}", index + 1, jobs.len(), job.url);
+        }
+        if let Some((book, output)) = layout.finished(job, convert) {
+            info!("Already finished: {}, skip", output.display());
+            rows.push(Row {
+                book,
+                status: Status::Skipped,
+                output: Some(output),
+            });
+            continue;
+        }
+        let result = if convert {
+            download_book(client, job, token, &layout, matches, &cancel).await
+        } else {
+            fetch(client, &job.url, token, &layout, &cancel).await
+        };
+        match result {
+            Ok((output, manifest)) => {
+                rows.push(Row {
+                    book: manifest.title,
+                    status: Status::Done,
+                    output: Some(output),
+                });
+            }
+            Err(e) if jobs.len() == 1 => return Err(e),
+            Err(e) => {
+                error!("{}", e);
+                rows.push(Row {
+                    book: job
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| book_id::parse(&job.url).unwrap_or(job.url.clone())),
+                    status: Status::Failed(e.to_string()),
+                    output: None,
+                });
+                // Without a token or after Ctrl+C the remaining books would fail the same way.
+                if e.downcast_ref::<error::Error>()
+                    .is_some_and(error::Error::stops_batch)
+                    || exit_code(e.as_ref()) == 130
+                {
+                    fatal = Some(e);
+                    break;
+                }
+            }
+        }
+    }
+    if jobs.len() > 1 {
+        cli::batch::print_summary(&rows);
+    }
+    if let Some(e) = fatal {
+        return Err(e);
+    }
+    let failed = rows
+        .iter()
+        .filter(|row| matches!(row.status, Status::Failed(_)))
+        .count();
+    if failed > 0 {
+        return Err(std::io::Error::other(format!(
+            "{} of {} books failed, run the same command again to retry them",
+            failed,
+            jobs.len()
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Parses the book and downloads its selected images, returning the directory they were saved
+/// to and the manifest.
+async fn fetch(
+    client: &Client,
+    url: &str,
+    token: &Token,
+    layout: &Layout,
+    cancel: &CancellationToken,
+) -> Result<(PathBuf, manifest::Manifest), Box<dyn std::error::Error>> {
+    let task = tokio::select! {
+        result = client.parse(url, &token.value) => token.check(result)?,
+        _ = cancel.cancelled() => return Err(interrupted().into()),
     };
-    if !success {
-        return Err(Box::new(std::io::Error::other("failed")).into());
+    let save_dir = layout.work_dir.join(&task.book_real_id);
+    fs::create_dir_all(&save_dir)?;
+    let mut manifest = manifest::Manifest::new(&task, &save_dir);
+    if let Ok(saved) = manifest::Manifest::load(&save_dir) {
+        manifest.pdfs = saved.pdfs;
     }
-    println!("Download complete");
-    convert::convert(
+    manifest.save(&save_dir)?;
+    if manifest.selected(&layout.selection).next().is_none() {
+        return Err(error::Error::EmptySelection.into());
+    }
+    let failures = client
+        .download(task, &save_dir, &layout.selection, cancel.clone())
+        .await?;
+    manifest.refresh(&save_dir);
+    manifest.save(&save_dir)?;
+    if cancel.is_cancelled() {
+        return Err(interrupted().into());
+    }
+    if !failures.is_empty() {
+        error!("Failed to download {} pages:", failures.len());
+        for failure in &failures {
+            error!("  {}: {}", failure.file_name, failure.reason);
+        }
+        return Err(std::io::Error::other(format!(
+            "{} pages failed, run the same command again to retry them",
+            failures.len()
+        ))
+        .into());
+    }
+    info!("Download complete");
+    Ok((save_dir, manifest))
+}
+
+/// Fetches the book and converts it, as the `download` subcommand does, returning the PDF
+/// and the manifest.
+async fn download_book(
+    client: &Client,
+    job: &Job,
+    token: &Token,
+    layout: &Layout,
+    matches: &ArgMatches,
+    cancel: &CancellationToken,
+) -> Result<(PathBuf, manifest::Manifest), Box<dyn std::error::Error>> {
+    let (save_dir, mut manifest) = fetch(client, &job.url, token, layout, cancel).await?;
+    // Numbered like the default name of `convert` if a PDF of that name exists.
+    let name = layout.pdf(job, Some(&manifest)).unwrap();
+    let pdf_path = convert_dir(
         &save_dir,
-        &save_dir.with_extension("pdf"),
-        *quality,
-        *auto_resize,
+        None,
+        Some(&name),
+        layout,
+        matches,
+        client.progress(),
+        cancel,
+    )
+    .await?;
+    // Kept with the manifest, so the book is known to be finished without its pages.
+    manifest.pdfs.insert(name, pdf_path.clone());
+    if *matches.get_one::<bool>("del_img").unwrap() {
+        manifest.remove_pages(&save_dir)?;
+    }
+    manifest.save(&save_dir)?;
+    Ok((pdf_path, manifest))
+}
+
+/// Builds the PDF from a download directory, using its manifest for the outline, the
+/// metadata and the default output name in the output directory when present, unless a
+/// `name` is given. An existing PDF is only replaced with `--overwrite`; the default name
+/// and `name` are numbered instead, but an explicit `output` is an error.
+async fn convert_dir(
+    dir: &Path,
+    output: Option<&Path>,
+    name: Option<&Path>,
+    layout: &Layout,
+    matches: &ArgMatches,
+    progress: &Progress,
+    cancel: &CancellationToken,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let options = convert::ConvertOptions {
+        quality: *matches.get_one::<i32>("quality").unwrap(),
+        auto_resize: *matches.get_one::<bool>("auto_resize").unwrap(),
+        color_mode: *matches.get_one::<ColorMode>("color_mode").unwrap(),
+        selection: layout.selection.clone(),
+    };
+
+    let (bookmarks, metadata, default_path) = match manifest::Manifest::load(dir) {
+        Ok(manifest) => (
+            manifest.bookmarks(),
+            manifest.metadata(),
+            layout
+                .output_dir
+                .join(manifest.pdf_file_name(&layout.template, &layout.selection)),
+        ),
+        Err(e) => {
+            warn!(
+                "No usable {} in {}: {}",
+                manifest::MANIFEST_NAME,
+                dir.display(),
+                e
+            );
+            let name = dir.file_name().unwrap_or_default().to_string_lossy();
+            (
+                Vec::new(),
+                convert::Metadata::default(),
+                layout.output_dir.join(format!("{}.pdf", name)),
+            )
+        }
+    };
+    let pdf_path = layout.output(output, name.unwrap_or(&default_path))?;
+    if let Some(parent) = pdf_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    convert::convert(
+        dir, &pdf_path, &options, &bookmarks, &metadata, progress, cancel,
     )
     .await?;
-    println!("Convert complete");
-    if *del_img {
-        fs::remove_dir_all(&save_dir)?;
+    info!("Convert complete: {}", pdf_path.display());
+    Ok(pdf_path)
+}
+
+/// Maps an error to the process exit code, see `error::Error::exit_code` for the preprocessor.
+fn exit_code(e: &(dyn std::error::Error + 'static)) -> u8 {
+    if let Some(e) = e.downcast_ref::<error::Error>() {
+        e.exit_code()
+    } else if let Some(e) = e.downcast_ref::<std::io::Error>()
+        && e.kind() == std::io::ErrorKind::Interrupted
+    {
+        130
+    } else {
+        1
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::from(exit_code(e.as_ref()))
+        }
+    }
+}
+
+async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let command = command!().version(env!("CARGO_PKG_VERSION")).author("Ricky1911").about(
+        "Download e-book from http://ereserves.lib.tsinghua.edu.cn. By default, four pages are downloaded at a time and the temporary images WILL BE preserved.
+        For example, \"thubookrs download https://ereserves.lib.tsinghua.edu.cn/bookDetail/c01e1db11c4041a39db463e810bac8f94af518935a1ec46ef --token eyJhb...\".
+        The token is the one from the FIRST request after logging in to the ereserves website, like \"/index?token=xxx\".
+        Use \"thubookrs login\" to log in with your username, password and two-factor authentication (2FA) code instead."
+    )
+    .subcommand_required(true)
+    .arg_required_else_help(true)
+    .arg(Arg::new("verbose").global(true).short('v').long("verbose").help("Optional. Print more details, including the HTTP requests. Repeat for even more.").action(ArgAction::Count))
+    .arg(Arg::new("quiet").global(true).long("quiet").help("Optional. Only print warnings and errors, without progress bars.").action(ArgAction::SetTrue).conflicts_with("verbose"))
+    .arg(Arg::new("log_format").global(true).long("log-format").help("Optional. The format of the log written to stderr. JSON disables the progress bars.").value_parser(["text", "json"]).default_value("text"))
+    .arg(Arg::new("config").global(true).long("config").env("THUBOOK_CONFIG").help("Optional. The TOML file with the default options. [default: config.toml in the thubookrs config directory, if any]").value_parser(value_parser!(PathBuf)))
+    .arg(Arg::new("profile").global(true).long("profile").env("THUBOOK_PROFILE").help("Optional. Also use the options of this profile of the config file, like \"archive\".").value_parser(value_parser!(String)))
+    .subcommand(
+        Command::new("download")
+            .about("Download the images of a book and convert them to PDF.")
+            .args(online_args())
+            .args(selection_args())
+            .arg(work_dir_arg())
+            .args(output_args())
+            .args(convert_args())
+            .arg(del_img_arg()),
+    )
+    .subcommand(
+        Command::new("fetch")
+            .about("Download the images of a book without converting them.")
+            .args(online_args())
+            .args(selection_args())
+            .arg(work_dir_arg()),
+    )
+    .subcommand(
+        Command::new("search")
+            .about("Search the books available to you by title, author or ISBN.")
+            .arg(Arg::new("query").required(true).num_args(1..).value_parser(value_parser!(String)))
+            .arg(token_arg())
+            .args(network_args()),
+    )
+    .subcommand(
+        Command::new("info")
+            .about("Print the details and the chapters of a book without downloading it.")
+            .arg(Arg::new("url").required(true).help("The book detail page or the book ID.").value_parser(value_parser!(String)))
+            .arg(token_arg())
+            .args(network_args()),
+    )
+    .subcommand(
+        Command::new("shelf")
+            .about("List your bookshelf and course reserves, and optionally download them, each list into its own folder.")
+            .arg(Arg::new("course").required(false).long("course").help("Optional. Only the lists whose name contains this text, like a course name.").value_parser(value_parser!(String)))
+            .arg(Arg::new("filter").required(false).long("filter").help("Optional. Only the books whose title or author contains this text.").value_parser(value_parser!(String)))
+            .arg(Arg::new("download").required(false).long("download").help("Optional. Download the listed books, the PDFs of each list into a folder named after it in the output directory.").action(ArgAction::SetTrue))
+            .arg(token_arg())
+            .args(network_args())
+            .arg(work_dir_arg())
+            .args(output_args())
+            .args(convert_args())
+            .arg(del_img_arg()),
+    )
+    .subcommand(
+        Command::new("login")
+            .about("Log in through the unified authentication, then download the book, or print the token if no URL is given.")
+            .arg(Arg::new("url").required(false).value_parser(value_parser!(String)))
+            .arg(Arg::new("username").required(false).short('u').long("username").help("Optional. The username of the unified authentication. [default: prompt]").value_parser(value_parser!(String)))
+            .arg(Arg::new("two_factor").required(false).long("2fa").help("Optional. How to receive the two-factor authentication code.").value_parser(["wechat", "mobile", "totp"]).default_value("totp"))
+            .args(network_args())
+            .args(selection_args())
+            .arg(work_dir_arg())
+            .args(output_args())
+            .args(convert_args())
+            .arg(del_img_arg()),
+    )
+    .subcommand(
+        Command::new("logout")
+            .about("Remove the token saved by the last run."),
+    )
+    .subcommand(
+        Command::new("convert")
+            .about("Convert an existing download directory to PDF, without token or network.")
+            .arg(Arg::new("dir").required(true).help("The directory of the downloaded images, like \"downloads/xxx\".").value_parser(value_parser!(PathBuf)))
+            .arg(Arg::new("output").required(false).short('o').long("output").help("Optional. The output PDF path, which must not exist yet without --overwrite. [default: named after the book title next to <dir>]").value_parser(value_parser!(PathBuf)).conflicts_with_all(["output_dir", "name"]))
+            .args(selection_args())
+            .args(output_args())
+            .args(convert_args()),
+    );
+    let (matches, settings) = cli::config::get_matches(command);
+
+    let json = matches.get_one::<String>("log_format").unwrap() == "json";
+    let quiet = *matches.get_one::<bool>("quiet").unwrap();
+    let level = match (quiet, matches.get_count("verbose")) {
+        (true, _) => LevelFilter::WARN,
+        (false, 0) => LevelFilter::INFO,
+        (false, 1) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
+    let bars = cli::progress::Bars::new(!quiet && !json);
+    cli::logging::init(level, json, &bars);
+    if !settings.is_empty() {
+        debug!("Options from the config file: {}", settings.join(", "));
+    }
+    match matches.subcommand() {
+        Some(("download", sub_matches)) => {
+            let client = client(sub_matches, &bars)?;
+            let (jobs, token) = jobs_and_token(sub_matches).await?;
+            run_jobs(&client, &jobs, &token, sub_matches, true).await?;
+        }
+        Some(("fetch", sub_matches)) => {
+            let client = client(sub_matches, &bars)?;
+            let (jobs, token) = jobs_and_token(sub_matches).await?;
+            run_jobs(&client, &jobs, &token, sub_matches, false).await?;
+        }
+        Some(("search", sub_matches)) => {
+            let client = client(sub_matches, &bars)?;
+            let token = token(sub_matches)?;
+            let query = sub_matches
+                .get_many::<String>("query")
+                .unwrap()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(" ");
+            let results = token.check(client.search(&query, &token.value).await)?;
+            cli::info::print_search(&results);
+        }
+        Some(("info", sub_matches)) => {
+            let client = client(sub_matches, &bars)?;
+            let token = token(sub_matches)?;
+            let url = sub_matches.get_one::<String>("url").unwrap();
+            let task = token.check(client.parse(url, &token.value).await)?;
+            cli::info::print_book(&task);
+        }
+        Some(("shelf", sub_matches)) => {
+            let client = client(sub_matches, &bars)?;
+            let token = token(sub_matches)?;
+            let mut shelves = token.check(client.shelves(&token.value).await)?;
+            let contains =
+                |text: &str, part: &str| text.to_lowercase().contains(&part.to_lowercase());
+            if let Some(course) = sub_matches.get_one::<String>("course") {
+                shelves.retain(|shelf| contains(&shelf.name, course));
+            }
+            if let Some(filter) = sub_matches.get_one::<String>("filter") {
+                for shelf in &mut shelves {
+                    shelf.books.retain(|book| {
+                        contains(&book.title, filter)
+                            || book.authors.iter().any(|author| contains(author, filter))
+                    });
+                }
+            }
+            cli::info::print_shelves(&shelves);
+            if *sub_matches.get_one::<bool>("download").unwrap() {
+                // A book on several lists is only downloaded into the first one.
+                let mut seen = HashSet::new();
+                let jobs: Vec<Job> = shelves
+                    .iter()
+                    .flat_map(|shelf| shelf.books.iter().map(move |book| (shelf, book)))
+                    .filter(|(_, book)| seen.insert(book.book_id.clone()))
+                    .map(|(shelf, book)| Job {
+                        folder: Some(shelf.name.clone()),
+                        ..Job::new(book.book_id.clone())
+                    })
+                    .collect();
+                if jobs.is_empty() {
+                    info!("No books to download");
+                } else {
+                    run_jobs(&client, &jobs, &token, sub_matches, true).await?;
+                }
+            }
+        }
+        Some(("login", sub_matches)) => {
+            let client = client(sub_matches, &bars)?;
+            let two_factor = match sub_matches
+                .get_one::<String>("two_factor")
+                .unwrap()
+                .as_str()
+            {
+                "wechat" => TwoFactor::WeChat,
+                "mobile" => TwoFactor::Mobile,
+                _ => TwoFactor::Totp,
+            };
+            let username = sub_matches.get_one::<String>("username");
+            let credentials = cli::prompt::credentials(username.map(String::as_str), two_factor)
+                .map_err(error::Error::Prompt)?;
+            let token = Token::new(
+                client.login(&credentials, cli::prompt::code).await?,
+                Source::Fresh,
+            );
+            info!("Login complete");
+            match sub_matches.get_one::<String>("url") {
+                Some(url) => {
+                    let job = Job::new(url.clone());
+                    run_jobs(&client, &[job], &token, sub_matches, true).await?;
+                }
+                None => {
+                    token.remember();
+                    println!("{}", token.value);
+                }
+            }
+        }
+        Some(("logout", _)) => {
+            Session::clear()?;
+            info!("The saved token was removed");
+        }
+        Some(("convert", sub_matches)) => {
+            let dir = sub_matches.get_one::<PathBuf>("dir").unwrap();
+            let output = sub_matches.get_one::<PathBuf>("output");
+            let mut layout = layout(sub_matches)?;
+            if sub_matches.get_one::<PathBuf>("output_dir").is_none() {
+                // Next to the download directory, like downloads/<title>.pdf
+                layout.output_dir = dir.parent().unwrap_or(Path::new("")).to_path_buf();
+            }
+            convert_dir(
+                dir,
+                output.map(PathBuf::as_path),
+                None,
+                &layout,
+                sub_matches,
+                &bars.progress(),
+                &watch_ctrl_c(),
+            )
+            .await?;
+        }
+        _ => unreachable!(),
     }
     Ok(())
 }
@@ -0,0 +1,246 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    convert::{Bookmark, Metadata},
+    download::get_file_name,
+    pre_process::DownloadTask,
+    selection::Selection,
+    template::Template,
+};
+
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+/// Replaces the characters that are invalid in file names on any platform.
+pub fn sanitize_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    name.trim().trim_end_matches('.').to_owned()
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PageEntry {
+    pub hfs_key: String,
+    pub file_name: String,
+    pub downloaded: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChapterEntry {
+    pub emid: String,
+    pub title: String,
+    pub level: u32,
+    pub pages: Vec<PageEntry>,
+}
+
+/// Everything known about a book after parsing, stored as `manifest.json` next to its images.
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub book_id: String,
+    pub book_real_id: String,
+    pub title: String,
+    pub authors: Vec<String>,
+    #[serde(default)]
+    pub publisher: String,
+    #[serde(default)]
+    pub isbn: String,
+    #[serde(default)]
+    pub publish_date: String,
+    pub chapters: Vec<ChapterEntry>,
+    /// The PDFs converted from the book, by the name they were meant to have, which differs
+    /// from their own when it was taken and they were numbered.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pdfs: BTreeMap<PathBuf, PathBuf>,
+}
+
+impl Manifest {
+    pub fn new(task: &DownloadTask, save_dir: &Path) -> Self {
+        let mut manifest = Self {
+            book_id: task.detail.book_id.clone(),
+            book_real_id: task.book_real_id.clone(),
+            title: task.detail.title.clone(),
+            authors: task.detail.authors.clone(),
+            publisher: task.detail.publisher.clone(),
+            isbn: task.detail.isbn.clone(),
+            publish_date: task.detail.publish_date.clone(),
+            chapters: task
+                .chapters
+                .iter()
+                .enumerate()
+                .map(|(chap_num, chapter)| ChapterEntry {
+                    emid: chapter.emid.clone(),
+                    title: chapter.title.clone(),
+                    level: chapter.level,
+                    pages: chapter
+                        .page_urls
+                        .iter()
+                        .enumerate()
+                        .map(|(page_num, hfs_key)| PageEntry {
+                            hfs_key: hfs_key.clone(),
+                            file_name: get_file_name(chap_num, page_num, hfs_key),
+                            downloaded: false,
+                        })
+                        .collect(),
+                })
+                .collect(),
+            pdfs: BTreeMap::new(),
+        };
+        manifest.refresh(save_dir);
+        manifest
+    }
+
+    pub fn load(dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let text = fs::read_to_string(dir.join(MANIFEST_NAME))?;
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    /// Writes the manifest through a temporary file so a crash never leaves a truncated one.
+    pub fn save(&self, dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_path = dir.join(format!(".{}", MANIFEST_NAME));
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(tmp_path, dir.join(MANIFEST_NAME))?;
+        Ok(())
+    }
+
+    /// Looks through the download directories under `root` for the book `book_id`.
+    pub fn find(root: &Path, book_id: &str) -> Option<(PathBuf, Self)> {
+        fs::read_dir(root)
+            .ok()?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|dir| dir.is_dir())
+            .find_map(|dir| {
+                let manifest = Self::load(&dir).ok()?;
+                (manifest.book_id == book_id).then_some((dir, manifest))
+            })
+    }
+
+    /// The pages of the book that are part of `selection`.
+    pub fn selected<'a>(&'a self, selection: &'a Selection) -> impl Iterator<Item = &'a PageEntry> {
+        self.chapters
+            .iter()
+            .enumerate()
+            .flat_map(|(chap_num, chapter)| {
+                chapter
+                    .pages
+                    .iter()
+                    .enumerate()
+                    .map(move |(page_num, page)| (chap_num, page_num, page))
+            })
+            .enumerate()
+            .filter(|(index, (chap_num, page_num, _))| {
+                selection.contains(*chap_num, *page_num, *index)
+            })
+            .map(|(_, (_, _, page))| page)
+    }
+
+    /// Whether every selected page was downloaded, as of the last [`Manifest::refresh`].
+    pub fn is_complete(&self, selection: &Selection) -> bool {
+        self.selected(selection).all(|page| page.downloaded)
+    }
+
+    /// Updates the download status of every page from the files present in `dir`.
+    pub fn refresh(&mut self, dir: &Path) {
+        for page in self
+            .chapters
+            .iter_mut()
+            .flat_map(|chapter| &mut chapter.pages)
+        {
+            page.downloaded = dir.join(&page.file_name).exists();
+        }
+    }
+
+    /// Deletes the downloaded pages from `dir`, leaving the manifest.
+    pub fn remove_pages(&mut self, dir: &Path) -> io::Result<()> {
+        for page in self
+            .chapters
+            .iter_mut()
+            .flat_map(|chapter| &mut chapter.pages)
+        {
+            match fs::remove_file(dir.join(&page.file_name)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => page.downloaded = false,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        Metadata {
+            title: self.title.clone(),
+            authors: self.authors.clone(),
+            publisher: self.publisher.clone(),
+            isbn: self.isbn.clone(),
+            publish_date: self.publish_date.clone(),
+        }
+    }
+
+    /// The output file name, `template` filled from the metadata, followed by the selection
+    /// unless the whole book is selected.
+    pub fn pdf_file_name(&self, template: &Template, selection: &Selection) -> String {
+        let mut name = template.render(&self.metadata());
+        if name.is_empty() {
+            name.clone_from(&self.book_real_id);
+        }
+        if !selection.is_all() {
+            name = format!("{} ({})", name, sanitize_file_name(&selection.to_string()));
+        }
+        format!("{}.pdf", name)
+    }
+
+    pub fn bookmarks(&self) -> Vec<Bookmark> {
+        self.chapters
+            .iter()
+            .map(|chapter| Bookmark {
+                title: chapter.title.clone(),
+                level: chapter.level,
+                pages: chapter.pages.len(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pdf_file_names() {
+        let manifest = Manifest {
+            book_id: "b1".to_owned(),
+            book_real_id: "r1".to_owned(),
+            title: "线性代数/第五版".to_owned(),
+            authors: Vec::new(),
+            publisher: String::new(),
+            isbn: String::new(),
+            publish_date: String::new(),
+            chapters: Vec::new(),
+            pdfs: BTreeMap::new(),
+        };
+        let all = Selection::default();
+        let template = Template::parse("{title}.pdf").unwrap();
+        assert_eq!(
+            manifest.pdf_file_name(&template, &all),
+            "线性代数_第五版.pdf"
+        );
+        let selection = Selection::parse(Some("2-3"), None).unwrap();
+        assert_eq!(
+            manifest.pdf_file_name(&template, &selection),
+            "线性代数_第五版 (chapters 2-3).pdf"
+        );
+        // Named after the book ID without any of the fields
+        let template = Template::parse("{isbn}.pdf").unwrap();
+        assert_eq!(manifest.pdf_file_name(&template, &all), "r1.pdf");
+    }
+}
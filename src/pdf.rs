@@ -0,0 +1,266 @@
+use std::io::{self, Write};
+
+use lopdf::{Dictionary, Object, ObjectId, StringFormat};
+
+/// Writes a PDF one object at a time, straight to `out`. Only the offsets of the objects are
+/// kept for the cross-reference table, so the memory used does not grow with the content.
+pub(crate) struct PdfWriter<W: Write> {
+    out: W,
+    position: u64,
+    /// The offset of every object by object number minus one, `None` until it is written.
+    offsets: Vec<Option<u64>>,
+}
+
+impl<W: Write> PdfWriter<W> {
+    pub(crate) fn new(out: W, version: &str) -> io::Result<Self> {
+        let mut writer = Self {
+            out,
+            position: 0,
+            offsets: Vec::new(),
+        };
+        writer.write_all(format!("%PDF-{}\n", version).as_bytes())?;
+        // A comment with bytes above 127 marks the file as binary for transfer tools
+        writer.write_all(b"%\xE2\xE3\xCF\xD3\n")?;
+        Ok(writer)
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.out.write_all(bytes)?;
+        self.position += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Takes the next object number, for an object that is referred to before it is written.
+    pub(crate) fn reserve(&mut self) -> ObjectId {
+        self.offsets.push(None);
+        (self.offsets.len() as u32, 0)
+    }
+
+    /// Writes `object` as the indirect object `id`. Streams are written without a copy of
+    /// their content.
+    pub(crate) fn write(&mut self, id: ObjectId, object: &Object) -> io::Result<()> {
+        self.offsets[id.0 as usize - 1] = Some(self.position);
+        let mut buffer = format!("{} {} obj\n", id.0, id.1).into_bytes();
+        match object {
+            Object::Stream(stream) => {
+                let mut dict = stream.dict.clone();
+                dict.set("Length", stream.content.len() as i64);
+                serialize_dict(&mut buffer, &dict);
+                buffer.extend_from_slice(b"\nstream\n");
+                self.write_all(&buffer)?;
+                self.write_all(&stream.content)?;
+                self.write_all(b"\nendstream\nendobj\n")
+            }
+            object => {
+                serialize(&mut buffer, object);
+                buffer.extend_from_slice(b"\nendobj\n");
+                self.write_all(&buffer)
+            }
+        }
+    }
+
+    /// Writes `object` under a new object number.
+    pub(crate) fn add(&mut self, object: &Object) -> io::Result<ObjectId> {
+        let id = self.reserve();
+        self.write(id, object)?;
+        Ok(id)
+    }
+
+    /// Writes the cross-reference table and `trailer`, and returns the flushed output.
+    pub(crate) fn finish(mut self, mut trailer: Dictionary) -> io::Result<W> {
+        let start = self.position;
+        let mut xref = format!("xref\n0 {}\n0000000000 65535 f\r\n", self.offsets.len() + 1);
+        for (index, offset) in self.offsets.iter().enumerate() {
+            let Some(offset) = offset else {
+                return Err(io::Error::other(format!(
+                    "PDF object {} was never written",
+                    index + 1
+                )));
+            };
+            xref += &format!("{:010} 00000 n\r\n", offset);
+        }
+        self.write_all(xref.as_bytes())?;
+        trailer.set("Size", self.offsets.len() as i64 + 1);
+        let mut buffer = b"trailer\n".to_vec();
+        serialize_dict(&mut buffer, &trailer);
+        buffer.extend_from_slice(format!("\nstartxref\n{}\n%%EOF\n", start).as_bytes());
+        self.write_all(&buffer)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn serialize(buffer: &mut Vec<u8>, object: &Object) {
+    match object {
+        Object::Null => buffer.extend_from_slice(b"null"),
+        Object::Boolean(value) => buffer.extend_from_slice(value.to_string().as_bytes()),
+        Object::Integer(value) => buffer.extend_from_slice(value.to_string().as_bytes()),
+        Object::Real(value) => buffer.extend_from_slice(value.to_string().as_bytes()),
+        Object::Name(name) => serialize_name(buffer, name),
+        Object::String(text, StringFormat::Literal) => {
+            buffer.push(b'(');
+            for &byte in text {
+                match byte {
+                    b'(' | b')' | b'\\' => buffer.extend_from_slice(&[b'\\', byte]),
+                    // A raw end of line in a string is read as \n
+                    b'\r' => buffer.extend_from_slice(b"\\r"),
+                    byte => buffer.push(byte),
+                }
+            }
+            buffer.push(b')');
+        }
+        Object::String(text, StringFormat::Hexadecimal) => {
+            buffer.push(b'<');
+            for byte in text {
+                buffer.extend_from_slice(format!("{:02X}", byte).as_bytes());
+            }
+            buffer.push(b'>');
+        }
+        Object::Array(array) => {
+            buffer.push(b'[');
+            for (index, item) in array.iter().enumerate() {
+                if index > 0 {
+                    buffer.push(b' ');
+                }
+                serialize(buffer, item);
+            }
+            buffer.push(b']');
+        }
+        Object::Dictionary(dict) => serialize_dict(buffer, dict),
+        Object::Reference(id) => {
+            buffer.extend_from_slice(format!("{} {} R", id.0, id.1).as_bytes())
+        }
+        // Streams are indirect objects, see PdfWriter::write
+        Object::Stream(_) => unreachable!("a stream inside another object"),
+    }
+}
+
+fn serialize_dict(buffer: &mut Vec<u8>, dict: &Dictionary) {
+    buffer.extend_from_slice(b"<<");
+    for (key, value) in dict.iter() {
+        serialize_name(buffer, key);
+        buffer.push(b' ');
+        serialize(buffer, value);
+    }
+    buffer.extend_from_slice(b">>");
+}
+
+fn serialize_name(buffer: &mut Vec<u8>, name: &[u8]) {
+    buffer.push(b'/');
+    for &byte in name {
+        // Delimiters, white-space and bytes outside of ! to ~ are written as #XX
+        if b" \t\n\r\x0C()<>[]{}/%#".contains(&byte) || !(b'!'..=b'~').contains(&byte) {
+            buffer.extend_from_slice(format!("#{:02X}", byte).as_bytes());
+        } else {
+            buffer.push(byte);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use lopdf::{Document, Stream, dictionary};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reloads_with_lopdf() {
+        let mut pdf = PdfWriter::new(Vec::new(), "1.7").unwrap();
+        let pages_id = pdf.reserve();
+        let outlines_id = pdf.reserve();
+        let mut page_ids = Vec::new();
+        for number in 0..3 {
+            let content = format!("BT /F1 12 Tf 72 720 Td (Page {}) Tj ET", number);
+            let content_id = pdf
+                .add(&Object::Stream(Stream::new(
+                    Dictionary::new(),
+                    content.into_bytes(),
+                )))
+                .unwrap();
+            let page = dictionary! {
+                "Type" => "Page",
+                "Parent" => pages_id,
+                "MediaBox" => vec![0.into(), 0.into(), 612.into(), 792.into()],
+                "Contents" => content_id,
+            };
+            page_ids.push(pdf.add(&page.into()).unwrap());
+        }
+        let kids = page_ids
+            .iter()
+            .map(|&id| id.into())
+            .collect::<Vec<Object>>();
+        let pages = dictionary! { "Type" => "Pages", "Kids" => kids, "Count" => 3 };
+        pdf.write(pages_id, &pages.into()).unwrap();
+        let title = b"Chapter (1) \\ one\rtwo".to_vec();
+        let item = dictionary! {
+            "Title" => Object::String(title.clone(), StringFormat::Literal),
+            "Parent" => outlines_id,
+            "Dest" => vec![page_ids[1].into(), "Fit".into()],
+        };
+        let item_id = pdf.add(&item.into()).unwrap();
+        let outlines = dictionary! {
+            "Type" => "Outlines", "First" => item_id, "Last" => item_id, "Count" => 1,
+        };
+        pdf.write(outlines_id, &outlines.into()).unwrap();
+        let catalog_id = pdf
+            .add(&dictionary! { "Type" => "Catalog", "Pages" => pages_id, "Outlines" => outlines_id }.into())
+            .unwrap();
+        let info = dictionary! {
+            "Title" => Object::string_literal("Linear Algebra (5th edition)"),
+            "Author" => Object::String(vec![0xFE, 0xFF, 0x73, 0x8B], StringFormat::Hexadecimal),
+        };
+        let info_id = pdf.add(&info.into()).unwrap();
+        let bytes = pdf
+            .finish(dictionary! { "Root" => catalog_id, "Info" => info_id })
+            .unwrap();
+
+        // Every entry of the cross-reference table points at its object
+        let tail = String::from_utf8_lossy(&bytes[bytes.len() - 32..]);
+        let start: usize = tail
+            .rsplit_once("startxref\n")
+            .unwrap()
+            .1
+            .lines()
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let xref = String::from_utf8(bytes[start..].to_vec()).unwrap();
+        let mut lines = xref.lines();
+        assert_eq!(lines.next(), Some("xref"));
+        assert_eq!(lines.next(), Some("0 12"));
+        assert_eq!(lines.next(), Some("0000000000 65535 f"));
+        // The entries are 20 bytes, ending with \r\n
+        assert!(xref.starts_with("xref\n0 12\n0000000000 65535 f\r\n"));
+        assert!(xref[10 + 12 * 20..].starts_with("trailer\n"));
+        for number in 1..=11 {
+            let offset: usize = lines.next().unwrap()[..10].parse().unwrap();
+            let header = format!("{} 0 obj\n", number);
+            assert!(bytes[offset..].starts_with(header.as_bytes()), "{}", number);
+        }
+
+        let path = std::env::temp_dir().join(format!("thubookrs-pdf-{}.pdf", std::process::id()));
+        fs::write(&path, &bytes).unwrap();
+        let document = Document::load(&path).await;
+        fs::remove_file(&path).unwrap();
+        let document = document.unwrap();
+        assert_eq!(document.trailer.get(b"Size").unwrap().as_i64().unwrap(), 12);
+        assert_eq!(document.objects.len(), 11);
+        assert_eq!(document.get_pages().len(), 3);
+        let content = document.get_page_content(page_ids[2]).unwrap();
+        assert_eq!(content, b"BT /F1 12 Tf 72 720 Td (Page 2) Tj ET");
+        let item = document.get_dictionary(item_id).unwrap();
+        assert_eq!(item.get(b"Title").unwrap().as_str().unwrap(), title);
+        let info = document.get_dictionary(info_id).unwrap();
+        assert_eq!(
+            info.get(b"Title").unwrap().as_str().unwrap(),
+            b"Linear Algebra (5th edition)"
+        );
+        assert_eq!(
+            info.get(b"Author").unwrap().as_str().unwrap(),
+            [0xFE, 0xFF, 0x73, 0x8B]
+        );
+    }
+}
@@ -1,24 +1,124 @@
 use std::{collections::HashMap, sync::Arc};
 
-use reqwest::{Client, ClientBuilder, header};
+use reqwest::{Client, ClientBuilder, RequestBuilder, Response, StatusCode, header};
 use reqwest_cookie_store::{CookieStore, CookieStoreMutex};
 use scraper::{Html, Selector};
+use serde::{Deserialize, de::DeserializeOwned};
 use serde_json::Value;
+use tracing::warn;
+
+use crate::{
+    book_id,
+    error::Error,
+    progress::{Progress, Stage},
+    throttle::Throttle,
+};
+
+pub struct Chapter {
+    pub emid: String,
+    pub title: String,
+    /// Nesting depth in the table of contents, starting from 1.
+    pub level: u32,
+    pub page_urls: Vec<String>,
+}
+
+pub struct BookDetail {
+    pub book_id: String,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub publisher: String,
+    pub isbn: String,
+    pub publish_date: String,
+}
+
+/// A book found by [`Preprocessor::search`] or listed on a [`Shelf`].
+pub struct BookSummary {
+    pub book_id: String,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub publisher: String,
+}
+
+/// A list of the user's books, the bookshelf or the reserves of a course.
+pub struct Shelf {
+    pub name: String,
+    pub books: Vec<BookSummary>,
+}
 
 pub struct DownloadTask {
+    pub detail: BookDetail,
     pub book_real_id: String,
     pub botu_read_kernel: String,
-    pub page_urls: Vec<Vec<String>>,
+    pub chapters: Vec<Chapter>,
+}
+
+fn split_authors(authors: &str) -> Vec<String> {
+    authors
+        .split([',', '，', ';', '；', '、'])
+        .map(|author| author.trim().to_owned())
+        .filter(|author| !author.is_empty())
+        .collect()
+}
+
+/// The maximum number of search results.
+pub const SEARCH_SIZE: usize = 50;
+/// The number of items asked for per page of the bookshelf and course lists.
+const LIST_SIZE: usize = 200;
+
+/// The body of every `userapi` response: `info` is "成功" on success.
+#[derive(Deserialize)]
+struct ApiResponse<T> {
+    info: String,
+    data: Option<T>,
+}
+
+/// A page of a list, like `{"list": [...], "total": 120}`.
+#[derive(Deserialize)]
+struct ListPage<T> {
+    list: Vec<T>,
+    total: usize,
+}
+
+/// A book in search results and on the bookshelf, like the `jc_ebook_vo` of getBookDetail.
+/// Some books have no author or publisher, which are null or left out then.
+#[derive(Deserialize)]
+struct BookItem {
+    #[serde(rename = "bookId")]
+    book_id: String,
+    title: String,
+    author: Option<String>,
+    publisher: Option<String>,
+}
+
+/// A course of the user, from getMyCourseList.
+#[derive(Deserialize)]
+struct CourseItem {
+    #[serde(rename = "courseId")]
+    course_id: String,
+    #[serde(rename = "courseName")]
+    course_name: String,
+}
+
+impl From<BookItem> for BookSummary {
+    fn from(item: BookItem) -> Self {
+        Self {
+            book_id: item.book_id.trim().to_owned(),
+            title: item.title.trim().to_owned(),
+            authors: split_authors(item.author.as_deref().unwrap_or_default()),
+            publisher: item.publisher.unwrap_or_default().trim().to_owned(),
+        }
+    }
 }
 
 pub struct Preprocessor {
     client: Client,
     client_no_redirect: Client,
     cookie_store: Arc<CookieStoreMutex>,
+    throttle: Arc<Throttle>,
 }
 
 impl Preprocessor {
-    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn new(throttle: Arc<Throttle>) -> Result<Self, Error> {
         let cookie_store = Arc::new(CookieStoreMutex::new(CookieStore::default()));
         let mut default_headers = header::HeaderMap::new();
         // default_headers.insert("Jcclient", header::HeaderValue::from_str(token.as_str())?);
@@ -34,153 +134,497 @@ impl Preprocessor {
                 .default_headers(default_headers)
                 .build()?,
             cookie_store,
+            throttle,
         })
     }
 
-    async fn get_scan_id(
-        &self,
-        url: &str,
-        token: &str,
-    ) -> Result<(String, String, String), Box<dyn std::error::Error>> {
-        let get_book_resource_url =
-            "https://ereserves.lib.tsinghua.edu.cn/userapi/ReadBook/GetResourcesUrl";
+    async fn send(&self, request: RequestBuilder) -> Result<Response, reqwest::Error> {
+        self.throttle.send(request).await
+    }
+
+    fn parse_json(endpoint: &'static str, text: &str) -> Result<Value, Error> {
+        serde_json::from_str(text).map_err(|source| Error::Json { endpoint, source })
+    }
+
+    /// Fails with [`Error::InvalidToken`] if the server rejected the token, either with a 401
+    /// or 403 status, the same code in the body, or a message about the login, which must be
+    /// checked before the data so an expired token is not reported as a missing book.
+    fn check_token(status: StatusCode, v: &Value) -> Result<(), Error> {
+        let info = v["info"].as_str().unwrap_or_default();
+        let code = v["code"]
+            .as_i64()
+            .or_else(|| v["code"].as_str().and_then(|code| code.parse().ok()));
+        let about_login = ["token", "登录", "过期", "失效", "授权"]
+            .iter()
+            .any(|word| info.to_lowercase().contains(word));
+        if matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+            Err(Error::InvalidToken(format!("HTTP {}", status)))
+        } else if matches!(code, Some(401 | 403)) || about_login {
+            Err(Error::InvalidToken(info.to_owned()))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn get_book_detail(&self, url: &str, token: &str) -> Result<(BookDetail, String), Error> {
+        let book_id = book_id::parse(url)?;
         let get_book_read_id_url = format!(
             "https://ereserves.lib.tsinghua.edu.cn/userapi/MyBook/getBookDetail?bookId={}",
-            url.rsplit('/').nth(0).unwrap()
+            book_id
         );
 
         let res = self
-            .client
-            .get(get_book_read_id_url)
-            .header("Jcclient", token)
-            .send()
-            .await?
-            .text()
+            .send(
+                self.client
+                    .get(get_book_read_id_url)
+                    .header("Jcclient", token),
+            )
             .await?;
-        let v: Value = serde_json::from_str(&res)?;
-        let book_real_id = v["data"]["jc_ebook_vo"]["urls"][0]["READURL"]
+        let status = res.status();
+        let text = res.text().await?;
+        let v = match Self::parse_json("getBookDetail", &text) {
+            Ok(v) => v,
+            Err(e) => {
+                // A rejected token may come without a JSON body
+                Self::check_token(status, &Value::Null)?;
+                return Err(e);
+            }
+        };
+        Self::check_token(status, &v)?;
+        let ebook = &v["data"]["jc_ebook_vo"];
+        if !ebook.is_object() {
+            return Err(Error::BookNotFound(book_id));
+        }
+        let book_real_id = ebook["urls"][0]["READURL"]
             .as_str()
-            .unwrap()
+            .filter(|id| !id.is_empty())
+            .ok_or_else(|| Error::NoReadUrl(book_id.clone()))?
             .to_owned();
+        let detail = BookDetail {
+            title: ebook["title"]
+                .as_str()
+                .map(|title| title.trim().to_owned())
+                .unwrap_or_else(|| book_id.clone()),
+            authors: split_authors(ebook["author"].as_str().unwrap_or_default()),
+            publisher: ebook["publisher"]
+                .as_str()
+                .unwrap_or_default()
+                .trim()
+                .to_owned(),
+            isbn: ebook["isbn"].as_str().unwrap_or_default().trim().to_owned(),
+            publish_date: ebook["publishdate"]
+                .as_str()
+                .unwrap_or_default()
+                .trim()
+                .to_owned(),
+            book_id,
+        };
+        Ok((detail, book_real_id))
+    }
 
+    /// Fetches page `page` (from 1) of a `userapi/MyBook` list with `size` items per page.
+    async fn get_page<T: DeserializeOwned>(
+        &self,
+        endpoint: &'static str,
+        query: &[(&str, &str)],
+        page: usize,
+        size: usize,
+        token: &str,
+    ) -> Result<ListPage<T>, Error> {
+        let url = format!(
+            "https://ereserves.lib.tsinghua.edu.cn/userapi/MyBook/{}",
+            endpoint
+        );
         let res = self
-            .client
-            .post(get_book_resource_url)
-            .json(&serde_json::json!({"id":book_real_id}))
-            .header("Jcclient", token)
-            .send()
+            .send(
+                self.client
+                    .get(url)
+                    .query(query)
+                    .query(&[("page", page), ("size", size)])
+                    .header("Jcclient", token),
+            )
+            .await?;
+        let status = res.status();
+        let text = res.text().await?;
+        let v = match Self::parse_json(endpoint, &text) {
+            Ok(v) => v,
+            Err(e) => {
+                Self::check_token(status, &Value::Null)?;
+                return Err(e);
+            }
+        };
+        Self::check_token(status, &v)?;
+        let response: ApiResponse<ListPage<T>> =
+            serde_json::from_value(v).map_err(|source| Error::Json { endpoint, source })?;
+        match response.data {
+            Some(data) => Ok(data),
+            // Like GetResourcesUrl, an expired token may only show in the message.
+            None if response.info != "成功" => Err(Error::InvalidToken(response.info)),
+            None => Err(Error::UnexpectedApiShape {
+                endpoint,
+                pointer: "/data",
+            }),
+        }
+    }
+
+    /// All items of a `userapi/MyBook` list, fetched page by page until `total` items or a
+    /// short page came back.
+    async fn get_list<T: DeserializeOwned>(
+        &self,
+        endpoint: &'static str,
+        query: &[(&str, &str)],
+        token: &str,
+    ) -> Result<Vec<T>, Error> {
+        let mut items = Vec::new();
+        for page in 1.. {
+            let mut list_page: ListPage<T> = self
+                .get_page(endpoint, query, page, LIST_SIZE, token)
+                .await?;
+            let short = list_page.list.len() < LIST_SIZE;
+            items.append(&mut list_page.list);
+            if short || items.len() >= list_page.total {
+                break;
+            }
+        }
+        Ok(items)
+    }
+
+    /// Searches the books available to the user by title, author or ISBN, returning the
+    /// first [`SEARCH_SIZE`] results.
+    pub async fn search(&self, query: &str, token: &str) -> Result<Vec<BookSummary>, Error> {
+        let page: ListPage<BookItem> = self
+            .get_page("searchBook", &[("keyword", query)], 1, SEARCH_SIZE, token)
+            .await?;
+        if page.total > page.list.len() {
+            warn!(
+                "{} books found, showing the first {}, refine the search to see the others",
+                page.total,
+                page.list.len()
+            );
+        }
+        Ok(page.list.into_iter().map(BookSummary::from).collect())
+    }
+
+    /// The user's bookshelf followed by the reserves of each of their courses.
+    pub async fn shelves(&self, token: &str) -> Result<Vec<Shelf>, Error> {
+        let items: Vec<BookItem> = self.get_list("getMyBookList", &[], token).await?;
+        let mut shelves = vec![Shelf {
+            name: "My bookshelf".to_owned(),
+            books: items.into_iter().map(BookSummary::from).collect(),
+        }];
+        let courses: Vec<CourseItem> = self.get_list("getMyCourseList", &[], token).await?;
+        for course in courses {
+            let course_id = course.course_id.trim();
+            let name = course.course_name.trim().to_owned();
+            if course_id.is_empty() {
+                warn!("Course {} has no ID, skip", name);
+                continue;
+            }
+            let items: Vec<BookItem> = self
+                .get_list("getCourseBookList", &[("courseId", course_id)], token)
+                .await?;
+            shelves.push(Shelf {
+                name,
+                books: items.into_iter().map(BookSummary::from).collect(),
+            });
+        }
+        Ok(shelves)
+    }
+
+    async fn get_scan_id(
+        &self,
+        book_real_id: &str,
+        token: &str,
+    ) -> Result<(String, String), Error> {
+        let get_book_resource_url =
+            "https://ereserves.lib.tsinghua.edu.cn/userapi/ReadBook/GetResourcesUrl";
+        let res = self
+            .send(
+                self.client
+                    .post(get_book_resource_url)
+                    .json(&serde_json::json!({"id":book_real_id}))
+                    .header("Jcclient", token),
+            )
             .await?
             .text()
             .await?;
-        let v: Value = serde_json::from_str(&res)?;
-        if v["info"].as_str().unwrap() != "成功" {
-            println!("{}", v);
-            panic!("Token error, please retry");
+        let v = Self::parse_json("GetResourcesUrl", &res)?;
+        let info = v["info"].as_str().ok_or(Error::UnexpectedApiShape {
+            endpoint: "GetResourcesUrl",
+            pointer: "/info",
+        })?;
+        if info != "成功" {
+            return Err(Error::InvalidToken(info.to_owned()));
         }
-        let book_access_url = v["data"].as_str().unwrap();
+        let book_access_url = v["data"].as_str().ok_or(Error::UnexpectedApiShape {
+            endpoint: "GetResourcesUrl",
+            pointer: "/data",
+        })?;
 
-        let res = self.client_no_redirect.get(book_access_url).send().await?;
+        let res = self
+            .send(self.client_no_redirect.get(book_access_url))
+            .await?;
         let botu_read_kernel = res
             .cookies()
             .find(|cookie| cookie.name() == "BotuReadKernel")
-            .unwrap()
+            .ok_or(Error::MissingCookie("BotuReadKernel"))?
             .value()
             .to_owned();
 
-        let res = self
-            .client
-            .get(res.headers().get("Location").unwrap().to_str()?)
-            .send()
-            .await?;
+        let location = res
+            .headers()
+            .get("Location")
+            .and_then(|location| location.to_str().ok())
+            .ok_or(Error::MissingRedirect)?;
+        let res = self.send(self.client.get(location)).await?;
         let doc = Html::parse_document(res.text().await?.as_str());
         let selector = Selector::parse("#scanid").unwrap();
         let scan_id = doc
             .select(&selector)
             .nth(0)
-            .unwrap()
-            .value()
-            .attr("value")
-            .unwrap()
+            .and_then(|element| element.value().attr("value"))
+            .ok_or_else(|| Error::ScanIdNotFound(book_real_id.to_owned()))?
             .to_owned();
 
-        Ok((botu_read_kernel, book_real_id, scan_id))
+        Ok((botu_read_kernel, scan_id))
     }
 
     async fn get_book_chapters(
         &self,
         botu_read_kernel: &str,
         scan_id: &str,
-    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    ) -> Result<Vec<Chapter>, Error> {
         let url = "https://ereserves.lib.tsinghua.edu.cn/readkernel/KernelAPI/BookInfo/selectJgpBookChapters";
         let mut form = HashMap::new();
         form.insert("SCANID", scan_id);
         let res = self
-            .client
-            .post(url)
-            .header("BotuReadKernel", botu_read_kernel)
-            .form(&form)
-            .send()
+            .send(
+                self.client
+                    .post(url)
+                    .header("BotuReadKernel", botu_read_kernel)
+                    .form(&form),
+            )
             .await?
             .text()
             .await?;
-        let v: Value = serde_json::from_str(res.as_str())?;
-        let info_array = v["data"].as_array().unwrap();
-        let emids: Vec<String> = info_array
+        let endpoint = "selectJgpBookChapters";
+        let v = Self::parse_json(endpoint, &res)?;
+        let info_array = v["data"].as_array().ok_or(Error::UnexpectedApiShape {
+            endpoint,
+            pointer: "/data",
+        })?;
+        let chapters = info_array
             .iter()
-            .map(|info| info["EMID"].as_str().unwrap().to_owned())
-            .collect();
-        Ok(emids)
+            .enumerate()
+            .map(|(index, info)| {
+                Ok(Chapter {
+                    emid: info["EMID"]
+                        .as_str()
+                        .ok_or(Error::UnexpectedApiShape {
+                            endpoint,
+                            pointer: "/data/*/EMID",
+                        })?
+                        .to_owned(),
+                    title: info["EFRAGMENTNAME"]
+                        .as_str()
+                        .map(|title| title.trim().to_owned())
+                        .filter(|title| !title.is_empty())
+                        .unwrap_or_else(|| format!("Chapter {}", index + 1)),
+                    level: info["ELEVEL"]
+                        .as_u64()
+                        .or_else(|| info["ELEVEL"].as_str().and_then(|level| level.parse().ok()))
+                        .map(|level| level.max(1) as u32)
+                        .unwrap_or(1),
+                    page_urls: Vec::new(),
+                })
+            })
+            .collect::<Result<Vec<Chapter>, Error>>()?;
+        Ok(chapters)
     }
 
     async fn get_book_pages(
         &self,
         botu_read_kernel: &str,
         book_real_id: &str,
-        emids: Vec<String>,
-    ) -> Result<Vec<Vec<String>>, Box<dyn std::error::Error>> {
+        chapters: &mut [Chapter],
+        progress: &Progress,
+    ) -> Result<(), Error> {
+        let phase = progress.phase(Stage::Parse, chapters.len() as u64);
         let url = "https://ereserves.lib.tsinghua.edu.cn/readkernel/KernelAPI/BookInfo/selectJgpBookChapter";
-        let mut page_urls = Vec::new();
-        for emid in emids {
+        for chapter in chapters {
             let mut form = HashMap::new();
-            form.insert("EMID", emid);
+            form.insert("EMID", chapter.emid.clone());
             form.insert("BOOKID", book_real_id.to_owned());
             let res = self
-                .client
-                .post(url)
-                .header("BotuReadKernel", botu_read_kernel)
-                .form(&form)
-                .send()
+                .send(
+                    self.client
+                        .post(url)
+                        .header("BotuReadKernel", botu_read_kernel)
+                        .form(&form),
+                )
                 .await?
                 .text()
                 .await?;
-            let v: Value = serde_json::from_str(res.as_str()).unwrap();
-            let info_array = v["data"]["JGPS"].as_array().unwrap();
-            page_urls.push(
-                info_array
-                    .iter()
-                    .map(|info| info["hfsKey"].as_str().unwrap().to_owned())
-                    .collect(),
-            );
+            let endpoint = "selectJgpBookChapter";
+            let v = Self::parse_json(endpoint, &res)?;
+            let info_array = v["data"]["JGPS"]
+                .as_array()
+                .ok_or(Error::UnexpectedApiShape {
+                    endpoint,
+                    pointer: "/data/JGPS",
+                })?;
+            chapter.page_urls = info_array
+                .iter()
+                .map(|info| {
+                    info["hfsKey"]
+                        .as_str()
+                        .map(str::to_owned)
+                        .ok_or(Error::UnexpectedApiShape {
+                            endpoint,
+                            pointer: "/data/JGPS/*/hfsKey",
+                        })
+                })
+                .collect::<Result<_, _>>()?;
+            phase.step(&format!(
+                "Parse complete: {}, {} pages",
+                chapter.title,
+                chapter.page_urls.len()
+            ));
         }
+        phase.finish();
 
-        Ok(page_urls)
+        Ok(())
     }
 
     pub async fn parse(
         &self,
         url: &str,
         token: &str,
-    ) -> Result<DownloadTask, Box<dyn std::error::Error>> {
-        let (botu_read_kernel, book_real_id, scan_id) = self.get_scan_id(url, token).await?;
-        let emids = self.get_book_chapters(&botu_read_kernel, &scan_id).await?;
-        let page_urls = self
-            .get_book_pages(&botu_read_kernel, &book_real_id, emids)
+        progress: &Progress,
+    ) -> Result<DownloadTask, Error> {
+        let (detail, book_real_id) = self.get_book_detail(url, token).await?;
+        let (botu_read_kernel, scan_id) = self.get_scan_id(&book_real_id, token).await?;
+        let mut chapters = self.get_book_chapters(&botu_read_kernel, &scan_id).await?;
+        self.get_book_pages(&botu_read_kernel, &book_real_id, &mut chapters, progress)
             .await?;
         self.cookie_store.lock().unwrap().clear();
         Ok(DownloadTask {
+            detail,
             book_real_id,
             botu_read_kernel,
-            page_urls,
+            chapters,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn check_token_reports_rejected_tokens() {
+        let expired = json!({"code": 401, "info": "请重新登录", "data": null});
+        assert!(matches!(
+            Preprocessor::check_token(StatusCode::OK, &expired),
+            Err(Error::InvalidToken(info)) if info == "请重新登录"
+        ));
+        let message = json!({"info": "token已失效", "data": null});
+        assert!(matches!(
+            Preprocessor::check_token(StatusCode::OK, &message),
+            Err(Error::InvalidToken(_))
+        ));
+        assert!(matches!(
+            Preprocessor::check_token(StatusCode::UNAUTHORIZED, &Value::Null),
+            Err(Error::InvalidToken(_))
+        ));
+    }
+
+    #[test]
+    fn search_results() {
+        let v = json!({
+            "info": "成功",
+            "data": {
+                "total": 2,
+                "list": [
+                    {"bookId": "b1", "title": " 线性代数 ", "author": "王某, 李某", "publisher": "清华大学出版社"},
+                    {"bookId": "b2", "title": "微积分", "author": "", "publisher": ""},
+                ],
+            },
+        });
+        let response: ApiResponse<ListPage<BookItem>> = serde_json::from_value(v).unwrap();
+        let page = response.data.unwrap();
+        assert_eq!(page.total, 2);
+        let books: Vec<BookSummary> = page.list.into_iter().map(BookSummary::from).collect();
+        assert_eq!(books[0].book_id, "b1");
+        assert_eq!(books[0].title, "线性代数");
+        assert_eq!(books[0].authors, ["王某", "李某"]);
+        assert_eq!(books[0].publisher, "清华大学出版社");
+        assert!(books[1].authors.is_empty());
+    }
+
+    #[test]
+    fn null_author_and_publisher() {
+        let v = json!({
+            "info": "成功",
+            "data": {
+                "total": 2,
+                "list": [
+                    {"bookId": "b1", "title": "线性代数", "author": null, "publisher": null},
+                    {"bookId": "b2", "title": "微积分"},
+                ],
+            },
+        });
+        let response: ApiResponse<ListPage<BookItem>> = serde_json::from_value(v).unwrap();
+        let books: Vec<BookSummary> = response
+            .data
+            .unwrap()
+            .list
+            .into_iter()
+            .map(BookSummary::from)
+            .collect();
+        assert_eq!(books.len(), 2);
+        for book in &books {
+            assert!(book.authors.is_empty());
+            assert_eq!(book.publisher, "");
+        }
+        assert_eq!(books[1].title, "微积分");
+    }
+
+    #[test]
+    fn missing_field_is_an_error() {
+        let v = json!({
+            "info": "成功",
+            "data": {"total": 1, "list": [{"bookId": "b1", "author": "", "publisher": ""}]},
+        });
+        let Err(e) = serde_json::from_value::<ApiResponse<ListPage<BookItem>>>(v) else {
+            panic!("a book without title was accepted");
+        };
+        assert!(e.to_string().contains("missing field `title`"), "{}", e);
+    }
+
+    #[test]
+    fn courses() {
+        let v = json!({
+            "info": "成功",
+            "data": {"total": 1, "list": [{"courseId": "c1", "courseName": "线性代数"}]},
+        });
+        let response: ApiResponse<ListPage<CourseItem>> = serde_json::from_value(v).unwrap();
+        let course = &response.data.unwrap().list[0];
+        assert_eq!(course.course_id, "c1");
+        assert_eq!(course.course_name, "线性代数");
+
+        let v = json!({"info": "成功", "data": {"total": 1, "list": [{"id": "c1"}]}});
+        assert!(serde_json::from_value::<ApiResponse<ListPage<CourseItem>>>(v).is_err());
+    }
+
+    #[test]
+    fn check_token_accepts_other_responses() {
+        let found = json!({"code": 0, "info": "成功", "data": {"jc_ebook_vo": {}}});
+        assert!(Preprocessor::check_token(StatusCode::OK, &found).is_ok());
+        // A missing book is not a token problem
+        let missing = json!({"info": "成功", "data": {"jc_ebook_vo": null}});
+        assert!(Preprocessor::check_token(StatusCode::OK, &missing).is_ok());
+    }
+}
@@ -0,0 +1,109 @@
+use std::sync::Arc;
+
+/// The stages a book goes through, in order. `Measure` only happens with auto resizing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Stage {
+    Parse,
+    Download,
+    Measure,
+    Resize,
+    Assemble,
+}
+
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Parse => "Parse",
+            Stage::Download => "Download",
+            Stage::Measure => "Measure",
+            Stage::Resize => "Resize",
+            Stage::Assemble => "Assemble",
+        }
+    }
+
+    /// What the steps of this stage count.
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Stage::Parse => "chapters",
+            _ => "pages",
+        }
+    }
+}
+
+/// Reported to the callback of [`Progress`] as a book is parsed, downloaded and converted.
+#[derive(Clone, Debug)]
+pub enum Event {
+    Started {
+        stage: Stage,
+        total: u64,
+    },
+    Step {
+        stage: Stage,
+        message: String,
+    },
+    /// Downloaded bytes, reported in addition to the steps.
+    Bytes {
+        stage: Stage,
+        bytes: u64,
+    },
+    Finished {
+        stage: Stage,
+    },
+}
+
+type Callback = Arc<dyn Fn(&Event) + Send + Sync>;
+
+/// Where progress events go. The default discards them.
+#[derive(Clone, Default)]
+pub struct Progress {
+    callback: Option<Callback>,
+}
+
+impl Progress {
+    pub fn new(callback: impl Fn(&Event) + Send + Sync + 'static) -> Self {
+        Self {
+            callback: Some(Arc::new(callback)),
+        }
+    }
+
+    fn emit(&self, event: Event) {
+        if let Some(callback) = &self.callback {
+            callback(&event);
+        }
+    }
+
+    /// Starts a stage of `total` steps.
+    pub fn phase(&self, stage: Stage, total: u64) -> Phase {
+        self.emit(Event::Started { stage, total });
+        Phase {
+            progress: self.clone(),
+            stage,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Phase {
+    progress: Progress,
+    stage: Stage,
+}
+
+impl Phase {
+    pub fn step(&self, message: &str) {
+        self.progress.emit(Event::Step {
+            stage: self.stage,
+            message: message.to_owned(),
+        });
+    }
+
+    pub fn add_bytes(&self, bytes: u64) {
+        self.progress.emit(Event::Bytes {
+            stage: self.stage,
+            bytes,
+        });
+    }
+
+    pub fn finish(&self) {
+        self.progress.emit(Event::Finished { stage: self.stage });
+    }
+}
@@ -0,0 +1,121 @@
+use reqwest::{
+    Url,
+    header::{self, HeaderMap},
+};
+
+/// Headers that carry the token or the reader session.
+const SECRET_HEADERS: [&str; 5] = [
+    "jcclient",
+    "cookie",
+    "set-cookie",
+    "botureadkernel",
+    "authorization",
+];
+
+pub(crate) fn redact_headers(headers: &HeaderMap) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            if SECRET_HEADERS.contains(&name.as_str()) {
+                format!("{}: <redacted>", name)
+            } else if name == header::LOCATION {
+                // Redirects carry the token after logging in.
+                let location = value
+                    .to_str()
+                    .ok()
+                    .and_then(|value| Url::parse(value).ok())
+                    .map_or_else(|| "<redacted>".to_owned(), |url| redact_url(&url));
+                format!("{}: {}", name, location)
+            } else {
+                format!("{}: {}", name, value.to_str().unwrap_or("<binary>"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Hides query parameters that look like credentials, such as `?token=`.
+pub(crate) fn redact_url(url: &Url) -> String {
+    let mut url = url.clone();
+    if url.query().is_some() {
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(key, value)| {
+                let lower = key.to_lowercase();
+                if lower.contains("token") || lower.contains("ticket") || lower.contains("session")
+                {
+                    (key.into_owned(), "<redacted>".to_owned())
+                } else {
+                    (key.into_owned(), value.into_owned())
+                }
+            })
+            .collect();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    url.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn urls() {
+        let url = Url::parse(
+            "https://ereserves.lib.tsinghua.edu.cn/index?token=eyJhbGci.secret&page=2&BotuSessionId=abc&q=%E7%BA%BF%E6%80%A7",
+        )
+        .unwrap();
+        let redacted = redact_url(&url);
+        assert_eq!(
+            redacted,
+            "https://ereserves.lib.tsinghua.edu.cn/index?token=%3Credacted%3E&page=2&BotuSessionId=%3Credacted%3E&q=%E7%BA%BF%E6%80%A7"
+        );
+        assert!(!redacted.contains("eyJhbGci"));
+        let url = Url::parse("https://id.tsinghua.edu.cn/do/off/ui/auth/login/check?ticket=ST-1")
+            .unwrap();
+        assert!(!redact_url(&url).contains("ST-1"));
+        // Nothing to hide
+        let url =
+            Url::parse("https://ereserves.lib.tsinghua.edu.cn/bookDetail/abc?page=1").unwrap();
+        assert_eq!(redact_url(&url), url.as_str());
+        let url = Url::parse("https://ereserves.lib.tsinghua.edu.cn/").unwrap();
+        assert_eq!(redact_url(&url), "https://ereserves.lib.tsinghua.edu.cn/");
+    }
+
+    #[test]
+    fn headers() {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("cookie", "BotuReadKernel=secret1"),
+            ("set-cookie", "JSESSIONID=secret2; Path=/"),
+            ("authorization", "Bearer secret3"),
+            ("jcclient", "secret4"),
+            ("content-type", "image/jpeg"),
+            (
+                "location",
+                "https://ereserves.lib.tsinghua.edu.cn/index?token=secret5&from=login",
+            ),
+        ] {
+            headers.insert(name, HeaderValue::from_static(value));
+        }
+        let redacted = redact_headers(&headers);
+        assert!(!redacted.contains("secret"), "{}", redacted);
+        for part in [
+            "cookie: <redacted>",
+            "set-cookie: <redacted>",
+            "authorization: <redacted>",
+            "jcclient: <redacted>",
+            "content-type: image/jpeg",
+            "location: https://ereserves.lib.tsinghua.edu.cn/index?token=%3Credacted%3E&from=login",
+        ] {
+            assert!(redacted.contains(part), "{} in {}", part, redacted);
+        }
+
+        // A relative location cannot be parsed, so it is hidden as a whole
+        let mut headers = HeaderMap::new();
+        headers.insert("location", HeaderValue::from_static("/index?token=secret6"));
+        assert_eq!(redact_headers(&headers), "location: <redacted>");
+    }
+}
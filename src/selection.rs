@@ -0,0 +1,203 @@
+use std::{fmt, ops::RangeInclusive};
+
+use crate::error::Error;
+
+/// Pages of one chapter, or of the whole book if `chapter` is `None`. Counted from 1.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct PageRange {
+    chapter: Option<usize>,
+    pages: RangeInclusive<usize>,
+}
+
+/// The chapters and pages to download and convert, the whole book if empty.
+///
+/// Chapters are numbered from 1 in the order `thubookrs info` lists them. Pages are numbered
+/// from 1 either through the whole book, like `10-20`, or within a chapter, like `2:10-20`.
+/// A page is selected if any of the chapters or pages includes it.
+///
+/// ```
+/// use thubookrs::selection::Selection;
+///
+/// let selection = Selection::parse(Some("3-5"), Some("1,4,7, 2:10-20")).unwrap();
+/// // Arguments are the chapter, the page in the chapter and the page in the book, from 0
+/// assert!(selection.contains(3, 0, 50));
+/// assert!(selection.contains(0, 0, 0));
+/// assert!(selection.contains(0, 6, 6));
+/// assert!(selection.contains(1, 9, 30));
+/// assert!(!selection.contains(1, 20, 41));
+/// assert!(!selection.contains(5, 0, 90));
+/// assert_eq!(selection.to_string(), "chapters 3-5, pages 1,4,7,2:10-20");
+///
+/// assert!(Selection::parse(None, None).unwrap().is_all());
+/// for (chapters, pages) in [
+///     (Some(""), None),
+///     (Some("0"), None),
+///     (Some("5-3"), None),
+///     (Some("2:1"), None),
+///     (None, Some("1-")),
+///     (None, Some("a")),
+///     (None, Some("2:")),
+///     (None, Some("1,,2")),
+/// ] {
+///     assert!(Selection::parse(chapters, pages).is_err(), "{:?} {:?}", chapters, pages);
+/// }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Selection {
+    chapters: Vec<RangeInclusive<usize>>,
+    pages: Vec<PageRange>,
+}
+
+fn parse_number(text: &str) -> Option<usize> {
+    text.trim().parse().ok().filter(|n| *n > 0)
+}
+
+/// Parses `N` or `N-M` with `1 <= N <= M`.
+fn parse_range(text: &str) -> Option<RangeInclusive<usize>> {
+    let (start, end) = match text.split_once('-') {
+        Some((start, end)) => (parse_number(start)?, parse_number(end)?),
+        None => (parse_number(text)?, parse_number(text)?),
+    };
+    (start <= end).then_some(start..=end)
+}
+
+fn parse_page_range(text: &str) -> Option<PageRange> {
+    match text.split_once(':') {
+        Some((chapter, pages)) => Some(PageRange {
+            chapter: Some(parse_number(chapter)?),
+            pages: parse_range(pages)?,
+        }),
+        None => Some(PageRange {
+            chapter: None,
+            pages: parse_range(text)?,
+        }),
+    }
+}
+
+/// Parses a comma separated list, failing on the first item `parse_item` rejects.
+fn parse_list<T>(text: &str, parse_item: impl Fn(&str) -> Option<T>) -> Result<Vec<T>, Error> {
+    text.split(',')
+        .map(|item| parse_item(item.trim()).ok_or_else(|| Error::InvalidSelection(text.to_owned())))
+        .collect()
+}
+
+fn format_range(range: &RangeInclusive<usize>) -> String {
+    if range.start() == range.end() {
+        range.start().to_string()
+    } else {
+        format!("{}-{}", range.start(), range.end())
+    }
+}
+
+impl Selection {
+    /// The selection of `--chapters` and `--pages`, like `3-5`, `1,4,7` or `2:10-20`.
+    pub fn parse(chapters: Option<&str>, pages: Option<&str>) -> Result<Self, Error> {
+        Ok(Self {
+            chapters: match chapters {
+                Some(chapters) => parse_list(chapters, parse_range)?,
+                None => Vec::new(),
+            },
+            pages: match pages {
+                Some(pages) => parse_list(pages, parse_page_range)?,
+                None => Vec::new(),
+            },
+        })
+    }
+
+    /// Whether this selects the whole book.
+    pub fn is_all(&self) -> bool {
+        self.chapters.is_empty() && self.pages.is_empty()
+    }
+
+    /// Whether page `page` of chapter `chapter`, which is page `index` of the book, is
+    /// selected. All three are counted from 0.
+    pub fn contains(&self, chapter: usize, page: usize, index: usize) -> bool {
+        self.is_all()
+            || self
+                .chapters
+                .iter()
+                .any(|range| range.contains(&(chapter + 1)))
+            || self.pages.iter().any(|range| match range.chapter {
+                Some(selected) => selected == chapter + 1 && range.pages.contains(&(page + 1)),
+                None => range.pages.contains(&(index + 1)),
+            })
+    }
+}
+
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if !self.chapters.is_empty() {
+            let chapters: Vec<String> = self.chapters.iter().map(format_range).collect();
+            parts.push(format!("chapters {}", chapters.join(",")));
+        }
+        if !self.pages.is_empty() {
+            let pages: Vec<String> = self
+                .pages
+                .iter()
+                .map(|range| match range.chapter {
+                    Some(chapter) => format!("{}:{}", chapter, format_range(&range.pages)),
+                    None => format_range(&range.pages),
+                })
+                .collect();
+            parts.push(format!("pages {}", pages.join(",")));
+        }
+        if parts.is_empty() {
+            write!(f, "all pages")
+        } else {
+            write!(f, "{}", parts.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_of_a_chapter() {
+        let selection = Selection::parse(None, Some("2:10-20")).unwrap();
+        // Chapter 2 is chapter 1 from 0, page 10 is page 9 from 0
+        assert!(!selection.contains(1, 8, 100));
+        assert!(selection.contains(1, 9, 100));
+        assert!(selection.contains(1, 19, 110));
+        assert!(!selection.contains(1, 20, 111));
+        // The same pages of another chapter, or of the book, are not selected
+        assert!(!selection.contains(0, 9, 9));
+        assert!(!selection.contains(2, 15, 15));
+        assert_eq!(selection.to_string(), "pages 2:10-20");
+    }
+
+    #[test]
+    fn overlapping_chapters_and_pages() {
+        let selection = Selection::parse(Some("2-3"), Some("2:1-5, 8-12")).unwrap();
+        // In both the chapters and the pages
+        assert!(selection.contains(1, 0, 10));
+        // In the chapters only
+        assert!(selection.contains(2, 30, 60));
+        // In the pages of the book only
+        assert!(selection.contains(0, 7, 7));
+        assert!(!selection.contains(0, 12, 12));
+        assert!(!selection.contains(3, 0, 70));
+        assert_eq!(selection.to_string(), "chapters 2-3, pages 2:1-5,8-12");
+    }
+
+    #[test]
+    fn invalid_selections() {
+        for (chapters, pages) in [
+            (Some("3-"), None),
+            (Some("0-2"), None),
+            (None, Some("2:0")),
+            (None, Some("2:5-3")),
+            (None, Some(":3")),
+        ] {
+            let e = Selection::parse(chapters, pages).unwrap_err();
+            assert!(
+                matches!(e, Error::InvalidSelection(_)),
+                "{:?} {:?}",
+                chapters,
+                pages
+            );
+        }
+    }
+}
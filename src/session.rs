@@ -0,0 +1,170 @@
+use std::{
+    fs, io,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+const SESSION_NAME: &str = "session.json";
+
+/// The token saved between runs, so it does not have to be passed on every command line.
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    pub token: String,
+    /// Seconds since the Unix epoch.
+    pub saved_at: u64,
+}
+
+impl Session {
+    pub fn new(token: &str) -> Self {
+        Self {
+            token: token.to_owned(),
+            saved_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+        }
+    }
+
+    /// `<config dir>/thubookrs/session.json`, like `~/.config/thubookrs/session.json` on Linux.
+    pub fn path() -> Option<PathBuf> {
+        Some(dirs::config_dir()?.join("thubookrs").join(SESSION_NAME))
+    }
+
+    /// The saved session, if there is a readable one.
+    pub fn load() -> Option<Self> {
+        Self::load_from(&Self::path()?)
+    }
+
+    fn load_from(path: &Path) -> Option<Self> {
+        let text = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&text).ok()
+    }
+
+    /// Saves the session readable by the current user only.
+    pub fn save(&self) -> io::Result<()> {
+        let path = Self::path().ok_or_else(|| io::Error::other("no config directory"))?;
+        self.save_to(&path)
+    }
+
+    fn save_to(&self, path: &Path) -> io::Result<()> {
+        let dir = path.parent().unwrap();
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(dir)?;
+        let tmp_path = dir.join(format!(".{}", SESSION_NAME));
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp_path)?;
+        // The mode above only applies to a new file, not to one left by an earlier run
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        drop(file);
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Removes the saved session, if any.
+    pub fn clear() -> io::Result<()> {
+        let Some(path) = Self::path() else {
+            return Ok(());
+        };
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// When the token stops working, read from the `exp` claim of the JWT. `None` if the
+    /// token does not say.
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        let payload = self.token.split('.').nth(1)?;
+        let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(payload.trim_end_matches('='))
+            .ok()?;
+        let claims: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+        Some(UNIX_EPOCH + Duration::from_secs(claims["exp"].as_u64()?))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at()
+            .is_some_and(|expires_at| expires_at <= SystemTime::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A JWT with `claims` as the payload, the header and the signature do not matter here.
+    fn jwt(claims: &str) -> String {
+        let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(claims);
+        format!("eyJhbGciOiJIUzI1NiJ9.{}.c2lnbmF0dXJl", payload)
+    }
+
+    #[test]
+    fn expiry_from_the_token() {
+        let session = Session::new(&jwt(r#"{"sub":"2020010001","exp":1700000000}"#));
+        assert_eq!(
+            session.expires_at(),
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+        assert!(session.is_expired());
+
+        let later = SystemTime::now() + Duration::from_secs(3600);
+        let exp = later.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let session = Session::new(&jwt(&format!(r#"{{"exp":{}}}"#, exp)));
+        assert!(!session.is_expired());
+
+        // Tokens that do not say are taken as valid
+        for token in [
+            jwt(r#"{"sub":"2020010001"}"#),
+            "opaque-token".to_owned(),
+            "a.%%%.c".to_owned(),
+        ] {
+            let session = Session::new(&token);
+            assert_eq!(session.expires_at(), None, "{}", token);
+            assert!(!session.is_expired());
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = std::env::temp_dir().join(format!("thubookrs-session-{}", std::process::id()));
+        let path = dir.join("thubookrs").join(SESSION_NAME);
+        let tmp_path = path.with_file_name(format!(".{}", SESSION_NAME));
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        // Left readable by everyone by an earlier run
+        fs::write(&tmp_path, b"stale").unwrap();
+        #[cfg(unix)]
+        fs::set_permissions(
+            &tmp_path,
+            std::os::unix::fs::PermissionsExt::from_mode(0o644),
+        )
+        .unwrap();
+
+        let session = Session::new("eyJ.token");
+        let saved = session.save_to(&path);
+        let loaded = Session::load_from(&path);
+        #[cfg(unix)]
+        let mode =
+            std::os::unix::fs::PermissionsExt::mode(&fs::metadata(&path).unwrap().permissions());
+        let leftover = tmp_path.exists();
+        fs::remove_dir_all(&dir).unwrap();
+
+        saved.unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.token, "eyJ.token");
+        assert_eq!(loaded.saved_at, session.saved_at);
+        assert!(!leftover);
+        #[cfg(unix)]
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(Session::load_from(&dir.join("missing.json")).is_none());
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{convert::Metadata, error::Error, manifest::sanitize_file_name};
+
+/// The fields a template can use.
+const FIELDS: [&str; 7] = [
+    "title",
+    "author",
+    "authors",
+    "publisher",
+    "year",
+    "date",
+    "isbn",
+];
+/// File names are limited to 255 bytes on most file systems, which is less than 90 Chinese
+/// characters. Leave room for the selection and the extension.
+const MAX_NAME_BYTES: usize = 180;
+
+/// A file name template like `{title} - {author} ({year})`, filled from the book metadata.
+///
+/// The fields are `{title}`, `{author}` (the first author), `{authors}`, `{publisher}`,
+/// `{year}`, `{date}` and `{isbn}`. Characters that are invalid in file names are replaced,
+/// and brackets left empty by missing fields are removed.
+///
+/// ```
+/// use thubookrs::{convert::Metadata, template::Template};
+///
+/// let metadata = Metadata {
+///     title: "线性代数导论: 第五版".to_owned(),
+///     authors: vec!["Gilbert Strang".to_owned(), "王某".to_owned()],
+///     publish_date: "2019-01".to_owned(),
+///     ..Default::default()
+/// };
+/// let template = Template::parse("{title} - {author} ({year})").unwrap();
+/// assert_eq!(template.render(&metadata), "线性代数导论_ 第五版 - Gilbert Strang (2019)");
+/// let template = Template::parse("{title} [{publisher}] {isbn}").unwrap();
+/// assert_eq!(template.render(&metadata), "线性代数导论_ 第五版");
+///
+/// for template in ["{name}", "{title", "title}", ""] {
+///     assert!(Template::parse(template).is_err(), "{}", template);
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template(String);
+
+impl Default for Template {
+    fn default() -> Self {
+        Self("{title}".to_owned())
+    }
+}
+
+impl Template {
+    /// Checks that every `{...}` in `template` is a known field.
+    pub fn parse(template: &str) -> Result<Self, Error> {
+        let invalid =
+            |reason: String| Error::InvalidTemplate(format!("\"{}\": {}", template, reason));
+        if template.trim().is_empty() {
+            return Err(invalid("the template is empty".to_owned()));
+        }
+        let mut rest = template;
+        while let Some(start) = rest.find(['{', '}']) {
+            if rest[start..].starts_with('}') {
+                return Err(invalid("unmatched }".to_owned()));
+            }
+            let Some(end) = rest[start..].find('}') else {
+                return Err(invalid("unmatched {".to_owned()));
+            };
+            let field = &rest[start + 1..start + end];
+            if !FIELDS.contains(&field) {
+                return Err(invalid(format!(
+                    "unknown field {{{}}}, expected one of {{{}}}",
+                    field,
+                    FIELDS.join("}, {")
+                )));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        Ok(Self(template.to_owned()))
+    }
+
+    /// The file name without extension, empty if the template only uses missing fields. A
+    /// `.pdf` at the end of the template is left out.
+    pub fn render(&self, metadata: &Metadata) -> String {
+        let year = metadata
+            .publish_date
+            .as_bytes()
+            .windows(4)
+            .find(|digits| digits.iter().all(u8::is_ascii_digit))
+            .map(|digits| String::from_utf8_lossy(digits).into_owned())
+            .unwrap_or_default();
+        let value = |field: &str| match field {
+            "title" => metadata.title.clone(),
+            "author" => metadata.authors.first().cloned().unwrap_or_default(),
+            "authors" => metadata.authors.join(", "),
+            "publisher" => metadata.publisher.clone(),
+            "year" => year.clone(),
+            "date" => metadata.publish_date.clone(),
+            "isbn" => metadata.isbn.clone(),
+            _ => unreachable!("checked by Template::parse"),
+        };
+        // One pass over the template, so braces in the values are kept as they are
+        let mut name = String::new();
+        let mut rest = self.0.as_str();
+        while let Some(start) = rest.find('{') {
+            let end = start + rest[start..].find('}').unwrap();
+            name.push_str(&rest[..start]);
+            name.push_str(value(&rest[start + 1..end]).trim());
+            rest = &rest[end + 1..];
+        }
+        name.push_str(rest);
+        // The extension is added by the caller, also for templates like `{title}.pdf`
+        if name.to_ascii_lowercase().ends_with(".pdf") {
+            name.truncate(name.len() - ".pdf".len());
+        }
+        tidy(&sanitize_file_name(&name))
+    }
+}
+
+/// Removes the brackets and separators left over by missing fields and shortens the name to
+/// what file systems allow.
+fn tidy(name: &str) -> String {
+    let mut name = name.to_owned();
+    for empty in ["()", "[]", "（）", "【】", "《》"] {
+        name = name.replace(empty, "");
+    }
+    let mut name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    let separators = |c: char| c.is_whitespace() || matches!(c, '-' | '_' | ',' | '—');
+    name = name.trim_matches(separators).to_owned();
+    while name.len() > MAX_NAME_BYTES {
+        name.pop();
+    }
+    name.trim_end_matches(separators).to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pdf_extension_is_left_out() {
+        let metadata = Metadata {
+            title: "线性代数: 第五版".to_owned(),
+            ..Default::default()
+        };
+        for template in ["{title}.pdf", "{title}.PDF", "{title}"] {
+            let template = Template::parse(template).unwrap();
+            assert_eq!(template.render(&metadata), "线性代数_ 第五版");
+        }
+        // Only at the end
+        let template = Template::parse("{title}.pdf.bak").unwrap();
+        assert_eq!(template.render(&metadata), "线性代数_ 第五版.pdf.bak");
+    }
+
+    #[test]
+    fn values_are_not_expanded_again() {
+        let metadata = Metadata {
+            title: "{isbn} and {year}".to_owned(),
+            authors: vec!["{title}".to_owned()],
+            publish_date: "2020".to_owned(),
+            isbn: "9787302".to_owned(),
+            ..Default::default()
+        };
+        let template = Template::parse("{title} - {author} {isbn}").unwrap();
+        assert_eq!(
+            template.render(&metadata),
+            "{isbn} and {year} - {title} 9787302"
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use std::{sync::Mutex, time::Duration};
+
+use rand::Rng;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+use crate::redact::{redact_headers, redact_url};
+
+/// The lowest rate accepted, one request every 100 seconds. Lower rates are raised to it.
+pub const MIN_RATE: f64 = 0.01;
+/// The interval used while slowing down when no rate limit was configured.
+const SLOW_DOWN_INTERVAL: Duration = Duration::from_millis(250);
+/// The interval is doubled at most this many times while the server keeps complaining.
+const MAX_SLOW_DOWN: u32 = 6;
+
+struct State {
+    next: Instant,
+    slow_down: u32,
+}
+
+/// A global pacer for every request sent to the ereserves server, shared by the
+/// preprocessor and the downloader.
+pub struct Throttle {
+    interval: Duration,
+    jitter: Duration,
+    state: Mutex<State>,
+}
+
+impl Throttle {
+    /// `requests_per_second` of `None` means no limit, values below [`MIN_RATE`] are raised to
+    /// it. Each request is additionally delayed by a random duration up to `jitter`.
+    pub fn new(requests_per_second: Option<f64>, jitter: Duration) -> Self {
+        Self {
+            interval: requests_per_second
+                .filter(|rate| !rate.is_nan())
+                .map_or(Duration::ZERO, |rate| {
+                    Duration::from_secs_f64(1.0 / rate.max(MIN_RATE))
+                }),
+            jitter,
+            state: Mutex::new(State {
+                next: Instant::now(),
+                slow_down: 0,
+            }),
+        }
+    }
+
+    /// Waits until the next request may be sent.
+    pub async fn wait(&self) {
+        let slot = {
+            let mut state = self.state.lock().unwrap();
+            let interval = if state.slow_down == 0 {
+                self.interval
+            } else {
+                self.interval.max(SLOW_DOWN_INTERVAL) * 2u32.pow(state.slow_down)
+            };
+            let slot = state.next.max(Instant::now());
+            state.next = slot + interval;
+            slot
+        };
+        let jitter = self.jitter.mul_f64(rand::rng().random_range(0.0..=1.0));
+        tokio::time::sleep_until(slot + jitter).await;
+    }
+
+    /// Adjusts the pace to a response: throttling and server errors slow all requests down,
+    /// successes gradually speed them up again.
+    pub fn observe(&self, status: StatusCode) {
+        let mut state = self.state.lock().unwrap();
+        if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            if state.slow_down < MAX_SLOW_DOWN {
+                state.slow_down += 1;
+                warn!(
+                    "Server responded {}, slowing down to one request per {:.1}s",
+                    status,
+                    (self.interval.max(SLOW_DOWN_INTERVAL) * 2u32.pow(state.slow_down))
+                        .as_secs_f64()
+                );
+            }
+        } else if status.is_success() {
+            state.slow_down = state.slow_down.saturating_sub(1);
+        }
+    }
+
+    /// Sends a request in turn, logging it with the secrets redacted and adjusting the pace
+    /// to its response.
+    pub(crate) async fn send(&self, request: RequestBuilder) -> Result<Response, reqwest::Error> {
+        let (client, request) = request.build_split();
+        let request = request?;
+        self.wait().await;
+        debug!(
+            "{} {} [{}]",
+            request.method(),
+            redact_url(request.url()),
+            redact_headers(request.headers())
+        );
+        let res = client.execute(request).await?;
+        debug!(
+            "{} {} [{}]",
+            res.status(),
+            redact_url(res.url()),
+            redact_headers(res.headers())
+        );
+        self.observe(res.status());
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The seconds after the start at which `count` requests are let through.
+    async fn slots(throttle: &Throttle, count: usize) -> Vec<f64> {
+        let start = Instant::now();
+        let mut slots = Vec::new();
+        for _ in 0..count {
+            throttle.wait().await;
+            slots.push((Instant::now() - start).as_secs_f64());
+        }
+        slots
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn spaces_requests_at_the_rate() {
+        let throttle = Throttle::new(Some(2.0), Duration::ZERO);
+        assert_eq!(slots(&throttle, 4).await, [0.0, 0.5, 1.0, 1.5]);
+        // A pause is not made up for with a burst
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(slots(&throttle, 2).await, [0.0, 0.5]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unlimited_and_too_low_rates() {
+        let throttle = Throttle::new(None, Duration::ZERO);
+        assert_eq!(slots(&throttle, 3).await, [0.0, 0.0, 0.0]);
+        let throttle = Throttle::new(Some(f64::NAN), Duration::ZERO);
+        assert_eq!(slots(&throttle, 2).await, [0.0, 0.0]);
+        let throttle = Throttle::new(Some(1e-30), Duration::ZERO);
+        assert_eq!(slots(&throttle, 2).await, [0.0, 100.0]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slows_down_on_errors() {
+        let throttle = Throttle::new(Some(10.0), Duration::ZERO);
+        throttle.observe(StatusCode::TOO_MANY_REQUESTS);
+        throttle.observe(StatusCode::SERVICE_UNAVAILABLE);
+        // 250ms doubled twice
+        assert_eq!(slots(&throttle, 2).await, [0.0, 1.0]);
+        throttle.observe(StatusCode::OK);
+        throttle.observe(StatusCode::NOT_FOUND);
+        assert_eq!(slots(&throttle, 2).await, [1.0, 1.5]);
+        for _ in 0..20 {
+            throttle.observe(StatusCode::BAD_GATEWAY);
+        }
+        assert_eq!(throttle.state.lock().unwrap().slow_down, MAX_SLOW_DOWN);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn jitter_stays_in_bounds() {
+        let throttle = Throttle::new(None, Duration::from_millis(300));
+        let start = Instant::now();
+        for _ in 0..50 {
+            let before = Instant::now();
+            throttle.wait().await;
+            assert!(Instant::now() - before <= Duration::from_millis(300));
+        }
+        assert!(Instant::now() - start > Duration::ZERO);
+    }
+}